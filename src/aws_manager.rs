@@ -1,88 +1,388 @@
+use std::collections::HashMap;
 use std::str::FromStr;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{RequestChecksumCalculation, ResponseChecksumValidation};
 use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadOutput;
 use aws_sdk_s3::operation::head_object::{HeadObjectError, HeadObjectOutput};
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::types::{ArchiveStatus, ChecksumAlgorithm, ChecksumMode, ChecksumType, CompletedMultipartUpload, CompletedPart, MetadataDirective, ServerSideEncryption, StorageClass};
 use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
 use aws_smithy_runtime_api::client::result::SdkError;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::time::Instant;
 use crate::errors::AWSError;
+use crate::initialization::AWS as AWSConfig;
+use crate::metrics::{observe, METRICS};
+use crate::chunk::PartPlan;
+use crate::storage_class::StorageClassRules;
 
-const CHUNK_SIZE: u64 = 1024 * 1024 * 10;
-const MAX_CHUNKS: u64 = 10000;
+const MIB: u64 = 1024 * 1024;
+const DEFAULT_PART_SIZE: u64 = MIB * 10;
+const DEFAULT_MULTIPART_THRESHOLD: u64 = MIB * 10;
+const MIN_PART_SIZE: u64 = MIB * 5;
+const MAX_PART_SIZE: u64 = MIB * 1024 * 5;
+const MAX_PARTS: u64 = 10000;
+const MAX_OBJECT_SIZE: u64 = MIB * 1024 * 1024 * 5;
+const MAX_COPY_SIZE: u64 = MIB * 1024 * 5;
+const COPY_PART_SIZE: u64 = MIB * 1024;
+
+/// Characters to percent-encode in a copy source, which must be url encoded
+const COPY_SOURCE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'/').remove(b'-').remove(b'_').remove(b'.').remove(b'~');
 
 pub struct ObjectInfo {
     pub mtime: Option<i64>,
     pub size: Option<u64>,
+    pub checksum: Option<String>,
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    pub storage_class: Option<StorageClass>,
+    pub archive_status: Option<ArchiveStatus>,
+    pub restore: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ObjectInfo {
+
+    /// Returns true if the object content can't be read without restoring it first, which is
+    /// the case for the Glacier Flexible Retrieval and Deep Archive storage classes and for
+    /// Intelligent-Tiering objects moved to an archive access tier, unless a restored copy is
+    /// available. Glacier Instant Retrieval and all other storage classes are readable
+    ///
+    pub fn is_archived(&self) -> bool {
+        let archived = match &self.storage_class {
+            Some(StorageClass::Glacier | StorageClass::DeepArchive) => true,
+            Some(StorageClass::IntelligentTiering) => self.archive_status.is_some(),
+            _ => false,
+        };
+        let restored = self.restore.as_deref().is_some_and(|r| r.contains("ongoing-request=\"false\""));
+
+        archived && !restored
+    }
+}
+
+/// Content and metadata of an object read from S3
+///
+pub struct ObjectContent {
+    pub metadata: HashMap<String, String>,
+    body: ByteStream,
+}
+
+impl ObjectContent {
+
+    /// Returns the next piece of content, or None when all content has been read
+    ///
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, AWSError> {
+        let bytes = self.body.try_next().await?.map(|b| b.to_vec());
+        if let Some(bytes) = &bytes {
+            METRICS.bytes.with_label_values(&["aws", "download"]).inc_by(bytes.len() as u64);
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// Additional checksum algorithm used to have S3 verify uploaded content
+/// Checksums are recorded as the algorithm name and the base64 encoded checksum separated by
+/// a colon, where checksums of multipart uploads are composite checksums (a checksum of the
+/// part checksums) ending with the number of parts, e.g. "CRC32C:yZRlqg==-3"
+///
+#[derive(Clone, Copy)]
+enum Checksum {
+    Crc32c,
+    Sha256,
+}
+
+impl Checksum {
+
+    /// Returns the checksum to use according to configuration, if not configured CRC32C for
+    /// AWS and none for S3-compatible services, since not all of them support additional checksums
+    ///
+    /// # Arguments
+    ///
+    /// * 'name' - the configured checksum algorithm
+    /// * 'custom_endpoint' - whether a custom endpoint URL is configured
+    fn new(name: &Option<String>, custom_endpoint: bool) -> Result<Option<Self>, AWSError> {
+        match name.as_deref() {
+            None if custom_endpoint => Ok(None),
+            None | Some("CRC32C") => Ok(Some(Checksum::Crc32c)),
+            Some("SHA256") => Ok(Some(Checksum::Sha256)),
+            Some("NONE") => Ok(None),
+            Some(other) => Err(AWSError(format!("unknown checksum algorithm: {}", other))),
+        }
+    }
+
+    /// Returns the S3 checksum algorithm
+    ///
+    fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Checksum::Crc32c => ChecksumAlgorithm::Crc32C,
+            Checksum::Sha256 => ChecksumAlgorithm::Sha256,
+        }
+    }
+
+    /// Returns the base64 encoded CRC32C or SHA-256 checksum of some content, whichever is used
+    ///
+    /// # Arguments
+    ///
+    /// * 'bytes' - the content
+    fn compute(&self, bytes: &[u8]) -> (Option<String>, Option<String>) {
+        match self {
+            Checksum::Crc32c => (Some(BASE64.encode(crc32c::crc32c(bytes).to_be_bytes())), None),
+            Checksum::Sha256 => (None, Some(BASE64.encode(Sha256::digest(bytes)))),
+        }
+    }
+
+    /// Returns the checksum to record from those returned by S3, if any
+    ///
+    /// # Arguments
+    ///
+    /// * 'crc32c' - CRC32C checksum returned by S3
+    /// * 'sha256' - SHA-256 checksum returned by S3
+    fn recorded(&self, crc32c: Option<&str>, sha256: Option<&str>) -> Option<String> {
+        match self {
+            Checksum::Crc32c => crc32c.map(|c| format!("CRC32C:{}", c)),
+            Checksum::Sha256 => sha256.map(|c| format!("SHA256:{}", c)),
+        }
+    }
 }
 
+/// Server side encryption settings applied to S3 requests
+/// All fields are None when no server side encryption is configured, leaving it to the
+/// bucket default encryption
+///
+#[derive(Default)]
+struct Encryption {
+    algorithm: Option<ServerSideEncryption>,
+    kms_key_id: Option<String>,
+    bucket_key_enabled: Option<bool>,
+    customer_algorithm: Option<String>,
+    customer_key: Option<String>,
+    customer_key_md5: Option<String>,
+}
+
+impl Encryption {
+
+    /// Creates encryption settings from configuration
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - AWS configuration parameters
+    fn new(config: &AWSConfig) -> Result<Self, AWSError> {
+        match config.server_side_encryption.as_deref() {
+            None => Ok(Encryption::default()),
+            Some("SSE-S3") => Ok(Encryption {
+                algorithm: Some(ServerSideEncryption::Aes256),
+                ..Default::default()
+            }),
+            Some("SSE-KMS") => Ok(Encryption {
+                algorithm: Some(ServerSideEncryption::AwsKms),
+                kms_key_id: config.sse_kms_key_id.clone(),
+                bucket_key_enabled: Some(config.sse_bucket_key_enabled),
+                ..Default::default()
+            }),
+            Some("SSE-C") => {
+                let key = BASE64.decode(&config.sse_customer_key)
+                    .map_err(|e| AWSError(format!("sse customer key: {}", e)))?;
+                if key.len() != 32 {
+                    return Err(AWSError::from("sse customer key must be 256 bits"));
+                }
+
+                Ok(Encryption {
+                    customer_algorithm: Some("AES256".to_string()),
+                    customer_key: Some(BASE64.encode(&key)),
+                    customer_key_md5: Some(BASE64.encode(Md5::digest(&key))),
+                    ..Default::default()
+                })
+            },
+            Some(other) => Err(AWSError(format!("unknown server side encryption: {}", other))),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct AWS {
     client: Client,
     bucket: String,
+    prefix: String,
+    part_size: u64,
+    multipart_threshold: u64,
+    storage_classes: StorageClassRules,
+    encryption: Encryption,
+    checksum: Option<Checksum>,
 }
 
 impl AWS {
 
     /// Creates a new AWS struct
+    /// If an endpoint url is given the client will talk to that S3-compatible service
+    /// (e.g. MinIO, Wasabi, Backblaze B2 or Ceph) instead of AWS S3
+    ///
+    /// All object names given to or returned from the AWS struct are relative to the
+    /// configured key prefix, if any
     ///
     /// # Arguments
     ///
-    /// * 'bucket' - the AWS S3 bucket to use
-    pub async fn new(bucket: &str) -> Self {
+    /// * 'config' - AWS configuration parameters
+    pub async fn new(config: &AWSConfig) -> Result<Self, AWSError> {
         let region_provider = RegionProviderChain::default_provider();
-        let config = aws_config::defaults(BehaviorVersion::latest())
+        let sdk_config = aws_config::defaults(BehaviorVersion::latest())
             .region(region_provider)
             .load()
             .await;
-        let client = Client::new(&config);
 
-        AWS { client, bucket: bucket.to_string() }
+        let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(config.force_path_style);
+
+        // S3-compatible services only get the checksums they require unless configured otherwise
+        if let Some(endpoint_url) = &config.endpoint_url {
+            builder = builder.endpoint_url(endpoint_url)
+                .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+                .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+        }
+        if let Some(calculation) = &config.request_checksum_calculation {
+            let calculation = RequestChecksumCalculation::from_str(calculation)
+                .map_err(|e| AWSError(format!("request_checksum_calculation: {}", e)))?;
+            builder = builder.request_checksum_calculation(calculation);
+        }
+        if let Some(validation) = &config.response_checksum_validation {
+            let validation = ResponseChecksumValidation::from_str(validation)
+                .map_err(|e| AWSError(format!("response_checksum_validation: {}", e)))?;
+            builder = builder.response_checksum_validation(validation);
+        }
+
+        let client = Client::from_conf(builder.build());
+        let storage_classes = StorageClassRules::new(&config.storage_class_rules, &config.default_storage_class)?;
+
+        let encryption = Encryption::new(config)?;
+
+        let prefix = Self::normalize_prefix(config.key_prefix.as_deref().unwrap_or_default());
+
+        let part_size = config.part_size.unwrap_or(DEFAULT_PART_SIZE);
+        if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&part_size) {
+            return Err(AWSError(format!("part size must be between {} and {} bytes", MIN_PART_SIZE, MAX_PART_SIZE)));
+        }
+        let multipart_threshold = config.multipart_threshold.unwrap_or(DEFAULT_MULTIPART_THRESHOLD);
+        if !(MIN_PART_SIZE..=MAX_PART_SIZE).contains(&multipart_threshold) {
+            return Err(AWSError(format!("multipart threshold must be between {} and {} bytes", MIN_PART_SIZE, MAX_PART_SIZE)));
+        }
+
+        let checksum = Checksum::new(&config.checksum_algorithm, config.endpoint_url.is_some())?;
+
+        Ok(AWS { client, bucket: config.bucket.clone(), prefix, part_size, multipart_threshold, storage_classes, encryption, checksum })
+    }
+
+    /// Returns a key prefix in the form used in the bucket, i.e. without a leading '/' and
+    /// with a trailing '/' unless empty
+    ///
+    /// # Arguments
+    ///
+    /// * 'prefix' - key prefix as configured
+    pub fn normalize_prefix(prefix: &str) -> String {
+        let prefix = prefix.trim_matches('/');
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        }
+    }
+
+    /// Returns the key prefix all object names are relative to
+    ///
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the full key in the bucket for an object name
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - name and path relative to the key prefix
+    fn key(&self, object_name: &str) -> String {
+        format!("{}{}", self.prefix, object_name)
+    }
+
+    /// Returns the storage class to upload a file with according to the configured rules
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path of the file
+    /// * 'size' - size of the file on OneDrive
+    pub fn storage_class(&self, path: &str, size: u64) -> Option<StorageClass> {
+        self.storage_classes.storage_class(path, size)
     }
 
     /// Puts an object to the S3 bucket
-    /// Should only be used for smaller objects such as 10MB or smaller, otherwise use the
+    /// Should only be used for objects up to the multipart threshold, otherwise use the
     /// multipart upload functions
     ///
+    /// It returns the checksum recorded by S3, if checksums are enabled
+    ///
     /// # Arguments
     ///
     /// * 'object_name' - name and path to be used in the S3 bucket
     /// * 'content_type' - the file Content-Type
-    /// * 'mtime' - last modification datetime as a timestamp
+    /// * 'metadata' - user metadata such as mtime (last modification datetime as a timestamp)
+    /// * 'storage_class' - storage class to store the object in, bucket default if None
     /// * 'bytes' - the file content
-    pub async fn put_object(&self, object_name: &str, content_type: &Option<String>, mtime: i64, bytes: Vec<u8>) -> Result<(), AWSError> {
+    pub async fn put_object(&self, object_name: &str, content_type: &Option<String>, metadata: &HashMap<String, String>, storage_class: &Option<StorageClass>, bytes: Vec<u8>) -> Result<Option<String>, AWSError> {
+        let (crc32c, sha256) = self.checksum.map(|c| c.compute(&bytes)).unwrap_or_default();
+        METRICS.bytes.with_label_values(&["aws", "upload"]).inc_by(bytes.len() as u64);
         let body = ByteStream::from(bytes);
-        let _ = self.client
+        let request = self.client
             .put_object()
             .bucket(&self.bucket)
-            .key(object_name)
-            .metadata("mtime", mtime.to_string())
+            .key(self.key(object_name))
+            .set_checksum_algorithm(self.checksum.map(|c| c.algorithm()))
+            .set_checksum_crc32_c(crc32c)
+            .set_checksum_sha256(sha256)
+            .set_metadata(Some(metadata.clone()))
             .set_content_type(content_type.clone())
+            .set_storage_class(storage_class.clone())
+            .set_server_side_encryption(self.encryption.algorithm.clone())
+            .set_ssekms_key_id(self.encryption.kms_key_id.clone())
+            .set_bucket_key_enabled(self.encryption.bucket_key_enabled)
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
             .body(body)
-            .send()
-            .await?;
+            .send();
+        let result = observe("aws", "put_object", request).await?;
 
-        Ok(())
+        Ok(self.checksum.and_then(|c| c.recorded(result.checksum_crc32_c(), result.checksum_sha256())))
     }
 
     /// Returns object information och which the mtime attribute is a timestamp
-    /// reflecting the last modified date time
+    /// reflecting the last modified date time, and size is the size of the original file
     ///
     /// # Arguments
     ///
     /// * 'object_name' - name and path to the S3 object
     pub async fn get_object_info(&self, object_name: &str) -> Result<Option<ObjectInfo>, AWSError> {
-        let result = self.client
+        let request = self.client
             .head_object()
             .bucket(&self.bucket)
-            .key(object_name)
-            .send()
-            .await;
+            .key(self.key(object_name))
+            .checksum_mode(ChecksumMode::Enabled)
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+            .send();
+        let start = Instant::now();
+        let result = request.await;
+
+        // A missing object is an expected answer rather than a failed request
+        let found_or_missing = result.as_ref().map_or_else(|e| e.raw_response().is_some_and(|r| r.status().as_u16() == 404), |_| true);
+        METRICS.request("aws", "head_object", start, found_or_missing);
 
         let response: Option<ObjectInfo> = match result {
             Ok(head) => { 
-                Some(Self::construct_object_info(head))
+                Some(self.construct_object_info(head))
             },
             Err(err) => {
                 Self::construct_object_info_error(err)?
@@ -98,27 +398,37 @@ impl AWS {
     /// # Arguments
     ///
     /// * 'head' - a HeadObjectOutput instance 
-    fn construct_object_info(head: HeadObjectOutput) -> ObjectInfo {
-        let mtime = match head.metadata {
-            Some(metadata) => {
-                if let Some(mtime) = metadata.get("mtime") {
-                    let trimmed = if mtime.contains('.') {
-                        mtime.split_once('.').unwrap().0
-                    } else {
-                        &mtime
-                    };
-
-                    i64::from_str(trimmed).ok()
-                } else {
-                    None
-                }
-            },
-            None => None
+    fn construct_object_info(&self, head: HeadObjectOutput) -> ObjectInfo {
+        let checksum = self.checksum.and_then(|c| c.recorded(head.checksum_crc32_c(), head.checksum_sha256()));
+        let metadata = head.metadata.unwrap_or_default();
+        let mtime = if let Some(mtime) = metadata.get("mtime") {
+            let trimmed = if mtime.contains('.') {
+                mtime.split_once('.').unwrap().0
+            } else {
+                mtime
+            };
+
+            i64::from_str(trimmed).ok()
+        } else {
+            None
         };
         
+        // Transformed (e.g. compressed) objects carry the original size as metadata
+        let content_length = head.content_length.map(|x| x as u64);
+        let size = metadata.get("size")
+            .and_then(|s| u64::from_str(s).ok())
+            .or(content_length);
+
         ObjectInfo {
             mtime,
-            size: head.content_length.map(|x| x as u64),
+            size,
+            checksum,
+            content_length,
+            content_type: head.content_type,
+            storage_class: head.storage_class,
+            archive_status: head.archive_status,
+            restore: head.restore,
+            metadata,
         }
     }
     
@@ -143,31 +453,175 @@ impl AWS {
         }
     }
     
-    /// Checks so the file size won't exceed max number of parts
-    /// 
+    /// Returns a list of all object names starting with the given prefix
+    ///
     /// # Arguments
-    /// 
-    /// * 'file_size' - size of file to upload
-    pub fn check_for_multipart_upload(file_size: u64) -> Result<(), AWSError> {
-        let mut chunk_count = (file_size / CHUNK_SIZE) + 1;
-        let size_of_last_chunk = file_size % CHUNK_SIZE;
-        if size_of_last_chunk == 0 {
-            chunk_count -= 1;
+    ///
+    /// * 'prefix' - object name prefix, an empty prefix lists the whole bucket
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, AWSError> {
+        let mut objects: Vec<String> = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let request = self.client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.key(prefix))
+                .set_continuation_token(continuation_token)
+                .send();
+            let result = observe("aws", "list_objects_v2", request).await?;
+
+            result.contents()
+                .iter()
+                .filter_map(|o| o.key())
+                .filter_map(|k| k.strip_prefix(&self.prefix))
+                .for_each(|k| objects.push(k.to_string()));
+
+            if result.is_truncated().unwrap_or(false) {
+                continuation_token = result.next_continuation_token().map(|t| t.to_string());
+            } else {
+                return Ok(objects);
+            }
         }
+    }
 
-        if file_size == 0 {
-            Err(AWSError::from("file size is zero"))
-        } else if chunk_count > MAX_CHUNKS {
-            Err(AWSError::from("chunk count exceeded maximum"))
-        } else {
-            Ok(())
+    /// Gets an object from the S3 bucket
+    /// The content is not read until requested from the returned ObjectContent
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - name and path to the S3 object
+    pub async fn get_object(&self, object_name: &str) -> Result<ObjectContent, AWSError> {
+        let request = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(object_name))
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+            .send();
+        let result = observe("aws", "get_object", request).await?;
+
+        Ok(ObjectContent {
+            metadata: result.metadata.unwrap_or_default(),
+            body: result.body,
+        })
+    }
+
+    /// Copies an object within the bucket, keeping its content type and storage class, and
+    /// unless new metadata is given also its metadata
+    /// Objects bigger than 5GB are copied using a multipart upload
+    ///
+    /// # Arguments
+    ///
+    /// * 'source' - AWS struct for the key prefix the object is copied from
+    /// * 'source_name' - name and path of the object to copy
+    /// * 'object_name' - name and path to be used for the copy
+    /// * 'metadata' - user metadata replacing that of the copied object
+    pub async fn copy_object(&self, source: &AWS, source_name: &str, object_name: &str, metadata: Option<&HashMap<String, String>>) -> Result<(), AWSError> {
+        let info = source.get_object_info(source_name).await?
+            .ok_or(AWSError(format!("object not found: {}", source_name)))?;
+        let copy_source = format!("{}/{}", source.bucket, utf8_percent_encode(&source.key(source_name), COPY_SOURCE_ENCODE_SET));
+        let size = info.content_length.unwrap_or_default();
+        let metadata_directive = metadata.map(|_| MetadataDirective::Replace);
+
+        if size <= MAX_COPY_SIZE {
+            let request = self.client
+                .copy_object()
+                .bucket(&self.bucket)
+                .key(self.key(object_name))
+                .copy_source(&copy_source)
+                .set_metadata_directive(metadata_directive)
+                .set_checksum_algorithm(self.checksum.map(|c| c.algorithm()))
+                .set_metadata(metadata.cloned())
+                .set_content_type(metadata.and(info.content_type.clone()))
+                .set_storage_class(info.storage_class.clone())
+                .set_server_side_encryption(self.encryption.algorithm.clone())
+                .set_ssekms_key_id(self.encryption.kms_key_id.clone())
+                .set_bucket_key_enabled(self.encryption.bucket_key_enabled)
+                .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+                .set_sse_customer_key(self.encryption.customer_key.clone())
+                .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+                .set_copy_source_sse_customer_algorithm(source.encryption.customer_algorithm.clone())
+                .set_copy_source_sse_customer_key(source.encryption.customer_key.clone())
+                .set_copy_source_sse_customer_key_md5(source.encryption.customer_key_md5.clone())
+                .send();
+            observe("aws", "copy_object", request).await?;
+
+            return Ok(());
+        }
+
+        let (mut upload_parts, upload_id) = self.create_multipart_upload(object_name, &info.content_type, metadata.unwrap_or(&info.metadata), &info.storage_class).await?;
+        let mut part_number = 1;
+        let mut from: u64 = 0;
+        while from < size {
+            let to = (from + COPY_PART_SIZE).min(size) - 1;
+            let request = self.client
+                .upload_part_copy()
+                .bucket(&self.bucket)
+                .key(self.key(object_name))
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .copy_source(&copy_source)
+                .copy_source_range(format!("bytes={}-{}", from, to))
+                .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+                .set_sse_customer_key(self.encryption.customer_key.clone())
+                .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+                .set_copy_source_sse_customer_algorithm(source.encryption.customer_algorithm.clone())
+                .set_copy_source_sse_customer_key(source.encryption.customer_key.clone())
+                .set_copy_source_sse_customer_key_md5(source.encryption.customer_key_md5.clone())
+                .send();
+            let upload_part_res = observe("aws", "upload_part_copy", request).await?;
+
+            let copy_part_result = upload_part_res.copy_part_result
+                .ok_or(AWSError::from("copy part result not retrieved"))?;
+            upload_parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(copy_part_result.e_tag)
+                    .set_checksum_crc32_c(copy_part_result.checksum_crc32_c)
+                    .set_checksum_sha256(copy_part_result.checksum_sha256)
+                    .part_number(part_number)
+                    .build(),
+            );
+            part_number += 1;
+            from = to + 1;
         }
+        self.complete_multipart_upload(object_name, &upload_id, upload_parts).await?;
+
+        Ok(())
     }
 
-    /// Returns the chunk size
+    /// Deletes an object from the S3 bucket
     ///
-    pub fn get_chunk_size() -> u64 {
-        CHUNK_SIZE
+    /// # Arguments
+    ///
+    /// * 'object_name' - name and path to the S3 object
+    pub async fn delete_object(&self, object_name: &str) -> Result<(), AWSError> {
+        let request = self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(object_name))
+            .send();
+        observe("aws", "delete_object", request).await?;
+
+        Ok(())
+    }
+
+    /// Returns the plan for a multipart upload of a file
+    /// The part size is the preferred part size unless the file needs bigger parts to stay
+    /// within the maximum number of parts, and is always a whole number of MiB
+    /// 
+    /// # Arguments
+    /// 
+    /// * 'file_size' - size of file to upload
+    pub fn part_plan(&self, file_size: u64) -> Result<PartPlan, AWSError> {
+        plan_parts(file_size, self.part_size)
+    }
+
+    /// Returns the file size above which files are uploaded using multipart upload
+    ///
+    pub fn multipart_threshold(&self) -> u64 {
+        self.multipart_threshold
     }
     
     /// Creates a multipart upload
@@ -179,16 +633,26 @@ impl AWS {
     ///
     /// * 'object_name' - name and path to be used in the S3 bucket
     /// * 'content_type' - the file Content-Type
-    /// * 'mtime' - last modification datetime as a timestamp
-    pub async fn create_multipart_upload(&self, object_name: &str, content_type: &Option<String>, mtime: i64) -> Result<(Vec<CompletedPart>, String), AWSError> {
-        let multipart_upload_res: CreateMultipartUploadOutput = self.client
+    /// * 'metadata' - user metadata such as mtime (last modification datetime as a timestamp)
+    /// * 'storage_class' - storage class to store the object in, bucket default if None
+    pub async fn create_multipart_upload(&self, object_name: &str, content_type: &Option<String>, metadata: &HashMap<String, String>, storage_class: &Option<StorageClass>) -> Result<(Vec<CompletedPart>, String), AWSError> {
+        let request = self.client
             .create_multipart_upload()
             .bucket(&self.bucket)
-            .key(object_name)
-            .metadata("mtime", mtime.to_string())
+            .key(self.key(object_name))
+            .set_checksum_algorithm(self.checksum.map(|c| c.algorithm()))
+            .set_checksum_type(self.checksum.map(|_| ChecksumType::Composite))
+            .set_metadata(Some(metadata.clone()))
             .set_content_type(content_type.clone())
-            .send()
-            .await?;
+            .set_storage_class(storage_class.clone())
+            .set_server_side_encryption(self.encryption.algorithm.clone())
+            .set_ssekms_key_id(self.encryption.kms_key_id.clone())
+            .set_bucket_key_enabled(self.encryption.bucket_key_enabled)
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+            .send();
+        let multipart_upload_res: CreateMultipartUploadOutput = observe("aws", "create_multipart_upload", request).await?;
 
         let upload_id = multipart_upload_res.upload_id().ok_or({
             AWSError::from("upload id not retrieved")
@@ -211,21 +675,31 @@ impl AWS {
     /// * 'bytes' - a vector of file data
     /// * 'upload_parts' - a mutable reference to upload_parts retrieved from the call to create_multipart_upload function
     pub async fn upload_part(&self, object_name: &str, upload_id: &str, part_number: i32, bytes: Vec<u8>, upload_parts: &mut Vec<CompletedPart>) -> Result<(), AWSError> {
+        let (crc32c, sha256) = self.checksum.map(|c| c.compute(&bytes)).unwrap_or_default();
+        METRICS.bytes.with_label_values(&["aws", "upload"]).inc_by(bytes.len() as u64);
         let stream = ByteStream::from(bytes);
         
-        let upload_part_res = self.client
+        let request = self.client
             .upload_part()
-            .key(object_name)
+            .key(self.key(object_name))
+            .set_checksum_algorithm(self.checksum.map(|c| c.algorithm()))
+            .set_checksum_crc32_c(crc32c)
+            .set_checksum_sha256(sha256)
             .bucket(&self.bucket)
             .upload_id(upload_id)
             .body(stream)
             .part_number(part_number)
-            .send()
-            .await?;
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+            .send();
+        let upload_part_res = observe("aws", "upload_part", request).await?;
 
         upload_parts.push(
             CompletedPart::builder()
                 .e_tag(upload_part_res.e_tag.unwrap_or_default())
+                .set_checksum_crc32_c(upload_part_res.checksum_crc32_c)
+                .set_checksum_sha256(upload_part_res.checksum_sha256)
                 .part_number(part_number)
                 .build(),
         );
@@ -235,25 +709,123 @@ impl AWS {
 
     /// Completes a multipart upload
     ///
+    /// It returns the composite checksum recorded by S3, if checksums are enabled
+    ///
     /// # Arguments
     ///
     /// * 'object_name' - name and path to be used in the S3 bucket
     /// * 'upload_id' - id retrieved from the call to create_multipart_upload function
     /// * 'upload_parts' - the final upload_parts
-    pub async fn complete_multipart_upload(&self, object_name: &str, upload_id: &str, upload_parts: Vec<CompletedPart>) -> Result<(), AWSError> {
+    pub async fn complete_multipart_upload(&self, object_name: &str, upload_id: &str, upload_parts: Vec<CompletedPart>) -> Result<Option<String>, AWSError> {
         let completed_multipart_upload: CompletedMultipartUpload = CompletedMultipartUpload::builder()
             .set_parts(Some(upload_parts))
             .build();
 
-        let _complete_multipart_upload_res = self.client
+        let request = self.client
             .complete_multipart_upload()
             .bucket(&self.bucket)
-            .key(object_name)
+            .key(self.key(object_name))
             .multipart_upload(completed_multipart_upload)
             .upload_id(upload_id)
-            .send()
-            .await?;
+            .set_sse_customer_algorithm(self.encryption.customer_algorithm.clone())
+            .set_sse_customer_key(self.encryption.customer_key.clone())
+            .set_sse_customer_key_md5(self.encryption.customer_key_md5.clone())
+            .send();
+        let complete_multipart_upload_res = observe("aws", "complete_multipart_upload", request).await?;
         
-        Ok(())
+        Ok(self.checksum.and_then(|c| c.recorded(complete_multipart_upload_res.checksum_crc32_c(), complete_multipart_upload_res.checksum_sha256())))
+    }
+}
+/// Returns the plan for a multipart upload of a file
+/// The part size is the preferred part size unless the file needs bigger parts to stay
+/// within the maximum number of parts, and is always a whole number of MiB
+///
+/// # Arguments
+///
+/// * 'file_size' - size of file to upload
+/// * 'part_size' - preferred part size
+fn plan_parts(file_size: u64, part_size: u64) -> Result<PartPlan, AWSError> {
+    if file_size == 0 {
+        return Err(AWSError::from("file size is zero"));
+    } else if file_size > MAX_OBJECT_SIZE {
+        return Err(AWSError::from("file size exceeds maximum object size"));
+    }
+
+    // Leave room for transformed (encrypted) content being slightly bigger than the file
+    let min_part_size = file_size.div_ceil(MAX_PARTS - MAX_PARTS / 100);
+    let part_size = part_size.max(min_part_size).next_multiple_of(MIB).min(MAX_PART_SIZE);
+
+    Ok(PartPlan::new(file_size, part_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_minimum_part_size() {
+        let plan = plan_parts(12 * MIB, MIN_PART_SIZE).unwrap();
+        assert_eq!(plan.part_size, MIN_PART_SIZE);
+        assert_eq!(plan.part_count, 3);
+
+        let plan = plan_parts(MIN_PART_SIZE, MIN_PART_SIZE).unwrap();
+        assert_eq!(plan.part_count, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn plan_part_limit() {
+        // At the preferred part size this would take exactly the maximum number of parts
+        let plan = plan_parts(DEFAULT_PART_SIZE * MAX_PARTS, DEFAULT_PART_SIZE).unwrap();
+        assert_eq!(plan.part_size, 11 * MIB);
+        assert!(plan.part_count <= MAX_PARTS - MAX_PARTS / 100);
+    }
+
+    #[test]
+    fn plan_over_100_gb() {
+        let size = 100 * 1024 * MIB + 1;
+        let plan = plan_parts(size, DEFAULT_PART_SIZE).unwrap();
+        assert_eq!(plan.part_size % MIB, 0);
+        assert!(plan.part_count <= MAX_PARTS - MAX_PARTS / 100);
+        assert!(plan.part_size * plan.part_count >= size);
+        assert!(plan.part_size * (plan.part_count - 1) < size);
+    }
+
+    fn object_info(storage_class: Option<StorageClass>, archive_status: Option<ArchiveStatus>, restore: Option<&str>) -> ObjectInfo {
+        ObjectInfo {
+            mtime: None,
+            size: None,
+            checksum: None,
+            content_length: None,
+            content_type: None,
+            storage_class,
+            archive_status,
+            restore: restore.map(str::to_string),
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn archived_storage_classes() {
+        for class in [StorageClass::Glacier, StorageClass::DeepArchive] {
+            assert!(object_info(Some(class.clone()), None, None).is_archived());
+            assert!(object_info(Some(class.clone()), None, Some("ongoing-request=\"true\"")).is_archived());
+            assert!(!object_info(Some(class), None, Some("ongoing-request=\"false\", expiry-date=\"Fri, 21 Dec 2012 00:00:00 GMT\"")).is_archived());
+        }
+        for class in [StorageClass::Standard, StorageClass::GlacierIr, StorageClass::StandardIa, StorageClass::OnezoneIa, StorageClass::IntelligentTiering] {
+            assert!(!object_info(Some(class), None, None).is_archived());
+        }
+        assert!(!object_info(None, None, None).is_archived());
+        assert!(object_info(Some(StorageClass::IntelligentTiering), Some(ArchiveStatus::ArchiveAccess), None).is_archived());
+        assert!(object_info(Some(StorageClass::IntelligentTiering), Some(ArchiveStatus::DeepArchiveAccess), None).is_archived());
+    }
+
+    #[test]
+    fn plan_small_and_invalid_sizes() {
+        assert!(plan_parts(0, DEFAULT_PART_SIZE).is_err());
+        assert!(plan_parts(MAX_OBJECT_SIZE + 1, DEFAULT_PART_SIZE).is_err());
+
+        let plan = plan_parts(1, DEFAULT_PART_SIZE).unwrap();
+        assert_eq!(plan.part_count, 1);
+        assert_eq!(plan.part_size, DEFAULT_PART_SIZE);
+    }
+}
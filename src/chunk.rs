@@ -1,8 +1,32 @@
-/// Iterator over file size in chunks
+/// Plan for splitting a file into parts of equal size, apart from the last part
+///
+pub struct PartPlan {
+    pub size: u64,
+    pub part_size: u64,
+    pub part_count: u64,
+}
+
+impl PartPlan {
+    /// Creates a new part plan
+    ///
+    /// # Arguments
+    ///
+    /// * 'size' - size of file to be split
+    /// * 'part_size' - size of each part
+    pub fn new(size: u64, part_size: u64) -> Self {
+        PartPlan {
+            size,
+            part_size,
+            part_count: size.div_ceil(part_size),
+        }
+    }
+}
+
+/// Iterator over file size in chunks, yielding part number and first and last byte of each
+/// chunk. A file of size 0 has no chunks
 /// 
 pub struct Chunk {
     from: u64,
-    to: u64,
     part: i32,
     size: u64,
     chunk_size: u64,
@@ -13,15 +37,13 @@ impl Chunk {
     /// 
     /// # Arguments
     /// 
-    /// * 'size' - size of file to be chunked
-    /// * 'chunk_size' - size of each chunk
-    pub fn new(size: u64, chunk_size: u64) -> Self {
+    /// * 'plan' - the part plan for the file to be chunked
+    pub fn new(plan: &PartPlan) -> Self {
         Chunk {
             from: 0,
-            to: 0,
             part: 1,
-            size,
-            chunk_size,
+            size: plan.size,
+            chunk_size: plan.part_size,
         }
     }
 }
@@ -32,18 +54,70 @@ impl Iterator for Chunk {
     type Item = (i32, u64, u64);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.to >= self.size - 1 { return None; }
-
-        self.to = self.from + self.chunk_size - 1;
-        if self.to > self.size - 1 {
-            self.to = self.size - 1;
-        }
+        if self.from >= self.size { return None; }
 
-        let result = Some((self.part, self.from, self.to));
+        let to = (self.from + self.chunk_size - 1).min(self.size - 1);
+        let result = Some((self.part, self.from, to));
 
-        self.from = self.to + 1;
+        self.from = to + 1;
         self.part += 1;
 
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn chunks(size: u64, part_size: u64) -> Vec<(i32, u64, u64)> {
+        Chunk::new(&PartPlan::new(size, part_size)).collect()
+    }
+
+    /// Checks that the chunks are numbered from 1 and cover the file exactly once
+    fn assert_covers(chunks: &[(i32, u64, u64)], size: u64, part_size: u64) {
+        assert_eq!(chunks.len() as u64, PartPlan::new(size, part_size).part_count);
+        let mut next = 0;
+        for (i, &(part, from, to)) in chunks.iter().enumerate() {
+            assert_eq!(part, i as i32 + 1);
+            assert_eq!(from, next);
+            assert!(to >= from && to - from < part_size);
+            next = to + 1;
+        }
+        assert_eq!(next, size);
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        assert!(chunks(0, 5 * MIB).is_empty());
+    }
+
+    #[test]
+    fn one_byte_is_one_chunk() {
+        assert_eq!(chunks(1, 5 * MIB), vec![(1, 0, 0)]);
+    }
+
+    #[test]
+    fn chunks_cover_file() {
+        for size in [5 * MIB - 1, 5 * MIB, 5 * MIB + 1, 12 * MIB] {
+            assert_covers(&chunks(size, 5 * MIB), size, 5 * MIB);
+        }
+        assert_eq!(chunks(5 * MIB, 5 * MIB), vec![(1, 0, 5 * MIB - 1)]);
+    }
+
+    #[test]
+    fn chunks_at_part_limit() {
+        let size = 100 * 1024 * MIB + 1;
+        let part_size = 11 * MIB;
+        let parts = chunks(size, part_size);
+        assert_covers(&parts, size, part_size);
+        assert!(parts.len() <= 10000);
+
+        // The byte just over 100 GiB is a part of its own at the preferred part size
+        let parts = chunks(size, 10 * MIB);
+        assert_covers(&parts, size, 10 * MIB);
+        assert_eq!(parts.last().unwrap(), &(10241, size - 1, size - 1));
+    }
+}
@@ -1,19 +1,63 @@
+use std::collections::HashMap;
 use std::ops::Add;
 use chrono::{DateTime, Local, NaiveTime, TimeDelta, Utc};
 use log::{error, info, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use tokio::sync::mpsc::Sender;
 use tokio::time::{Instant, Duration};
 use crate::aws_manager::AWS;
 use crate::chunk::Chunk;
+use crate::compression::CompressionRules;
+use crate::crypto::MasterKey;
 use crate::initialization::Config;
 use crate::errors::CloudSyncError;
-use crate::onedrive_manager::OneDrive;
-use crate::token_manager::Tokens;
+use crate::history::{History, RunRecord, Trigger};
+use crate::mail_manager::Mail;
+use crate::report::run_report;
+use crate::manifest::{Manifest, ManifestEntry, BLOB_PREFIX};
+use crate::metrics::{result, METRICS};
+use crate::object_key::KeyMapper;
+use crate::onedrive_manager::{ItemInfo, OneDrive};
+use crate::scrub::scrub;
+use crate::status::{Progress, RunSummary, SyncState, SyncStatus};
+use crate::token_manager::{grant_url, Tokens};
+use crate::transform::UploadTransform;
+
+/// Characters to percent-encode in metadata values, in addition to all non-ASCII characters
+const METADATA_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%');
+
+/// Maximum length of the encoded path stored as metadata, since all user metadata of an
+/// object must fit in 2 KB
+const MAX_PATH_METADATA: usize = 1024;
+
+/// Maximum size of all user metadata of an object, counted as the UTF-8 bytes of all keys and values
+const MAX_USER_METADATA: usize = 2048;
+
+/// Metadata holding the name and path of a file, dropped in this order when all user metadata
+/// exceeds MAX_USER_METADATA since names and paths are also kept in the manifest
+const NAME_METADATA: [&str; 3] = ["path", "name", "cse-path"];
+
+/// Default number of times a file is uploaded before giving up when verification fails
+const DEFAULT_VERIFY_ATTEMPTS: u32 = 3;
 
 struct Mgr<'a> {
     one_drive: OneDrive,
     aws: AWS,
     tokens: Tokens,
+    master_key: Option<MasterKey>,
+    compression: CompressionRules,
+    keys: KeyMapper,
+    manifest: Manifest,
+    run: RunRecord,
     config: &'a Config,
+    status: &'a SyncStatus,
+}
+
+/// What was stored by one upload, to verify the object against
+struct Upload {
+    checksum: Option<String>,
+    length: u64,
+    metadata: HashMap<String, String>,
 }
 
 /// Sync start point
@@ -23,19 +67,37 @@ struct Mgr<'a> {
 /// # Arguments
 ///
 /// * 'config' - configuration struct
-pub async fn sync(config: &Config) {
+/// * 'status' - status shared with the web server
+/// * 'mail' - sender of notifications to the notifier
+pub async fn sync(config: &Config, status: &SyncStatus, mail: &Sender<Mail>) {
+    let mut trigger = Trigger::Scheduled;
     loop {
-        match sync_loop(&config).await {
+        match sync_loop(config, status, mail, trigger).await {
             Ok(_) => {
                 info!("sync terminated");
                 break;
             },
             Err(e) => {
+                METRICS.runs.with_label_values(&["error"]).inc();
+                METRICS.errors.with_label_values(&[e.kind()]).inc();
+                METRICS.retries.with_label_values(&["sync"]).inc();
+                status.update(|s| s.progress = None);
+                trigger = Trigger::Retry;
+
+                // The error is already in the run report, the expired token also needs action
                 match e {
                     CloudSyncError::TokenExpiredWarning => { 
-                        warn!(target: "mail", "token expired, visit http://<host>:8000/grant to re-authorize") 
+                        warn!(target: "mail", "OneDrive authorization expired, visit {} to re-authorize", grant_url(&config.onedrive));
+                        status.update(|s| {
+                            s.state = SyncState::Paused;
+                            s.tokens.valid = false;
+                        });
+                        status.error("token expired".to_string());
+                    },
+                    err => {
+                        error!("sync failed: {}", err);
+                        status.error(format!("sync failed: {}", err));
                     },
-                    err => { error!(target: "mail", "sync failed: {}", err.to_string()) },
                 }
             }
         }
@@ -43,52 +105,192 @@ pub async fn sync(config: &Config) {
 }
 
 /// Main cloud synchronization loop
+/// Every run, also a failed one, is recorded in the run history and reported by mail
 ///
 /// # Arguments
 ///
 /// * 'config' - configuration struct
-async fn sync_loop(config: &Config) -> Result<(), CloudSyncError> {
-    sleep_until_time(&config.general.sync_time).await;
-    
+/// * 'status' - status shared with the web server
+/// * 'mail' - sender of notifications to the notifier
+/// * 'trigger' - what started the first run
+async fn sync_loop(config: &Config, status: &SyncStatus, mail: &Sender<Mail>, trigger: Trigger) -> Result<(), CloudSyncError> {
+    sleep_until_time(&config.general.sync_time, status).await;
+
+    let history = History::new(&config.general);
+    let mut mgr = match new_mgr(config, status, trigger).await {
+        Ok(mgr) => mgr,
+        Err(e) => {
+            let result = Err(e);
+            end_run(&history, mail, &mut RunRecord::new(trigger), &result).await;
+            return result;
+        },
+    };
+
+    let mut trigger = trigger;
+    loop {
+        mgr.run = RunRecord::new(trigger);
+        let result = sync_run(&mut mgr).await;
+        end_run(&history, mail, &mut mgr.run, &result).await;
+        result?;
+
+        if config.scrub.enabled {
+            info!("scrubbing objects!");
+            status.update(|s| s.state = SyncState::Scrubbing);
+            if let Err(e) = scrub(config, &mut mgr.one_drive, &mut mgr.tokens, &mgr.aws, &mgr.master_key, &mgr.manifest).await {
+                error!(target: "mail", "scrub failed: {}", e);
+                status.error(format!("scrub failed: {}", e));
+            }
+        }
+
+        sleep_until_time(&config.general.sync_time, status).await;
+        trigger = Trigger::Scheduled;
+    }
+}
+
+/// Returns the managers needed for a sync
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'status' - status shared with the web server
+/// * 'trigger' - what started the first run
+async fn new_mgr<'a>(config: &'a Config, status: &'a SyncStatus, trigger: Trigger) -> Result<Mgr<'a>, CloudSyncError> {
     let tokens = Tokens::from_file(&config.onedrive.tokens_path).await?;
+    status.tokens(&tokens, &config.onedrive);
     let one_drive = OneDrive::new(&config.onedrive.delta_link_path, tokens.get_access_token())?;
-    let aws = AWS::new(&config.aws.bucket).await;
-    
-    let mut mgr = Mgr {
+    let aws = AWS::new(&config.aws).await?;
+    let master_key = if config.aws.client_side_encryption {
+        Some(MasterKey::new(&config.aws.cse_master_key)?)
+    } else {
+        None
+    };
+    let compression = CompressionRules::new(&config.compression)?;
+    let keys = KeyMapper::new(&master_key, &config.aws)?;
+    let manifest = Manifest::from_file(&config.manifest_path()).await?;
+
+    Ok(Mgr {
         one_drive,
         aws,
         tokens,
+        master_key,
+        compression,
+        keys,
+        manifest,
+        run: RunRecord::new(trigger),
         config,
-    };
-    
-    loop {
-        check_tokens(&mut mgr).await?;
-        let mut updated = 0;
-        let mut added = 0;
-
-        info!("get OneDrive deltas!");
-        let deltas = mgr.one_drive.get_delta().await?;
-        if !deltas.is_empty() {
-            info!("checking objects!");
-            for f in deltas.into_iter().filter(|f| f.file) {
-                if let Some(t) = &mgr.aws.get_object_info(&f.filename).await? {
-                    if backup_needed(f.size, t.size, f.mtime, t.mtime).await? {
-                        info!("updating file: {:?}", f.filename);
-                        backup_file(&mut mgr, &f.item_id, &f.filename, f.size, &f.content_type, f.mtime).await?;
-                        updated += 1;
-                    }
+        status,
+    })
+}
+
+/// Finishes the record of a run, saves it to the run history and mails the run report
+///
+/// # Arguments
+///
+/// * 'history' - the run history
+/// * 'mail' - sender of notifications to the notifier
+/// * 'run' - the run record
+/// * 'result' - the result of the run
+async fn end_run(history: &History, mail: &Sender<Mail>, run: &mut RunRecord, result: &Result<(), CloudSyncError>) {
+    run.finish(result.as_ref().err().map(|e| e.to_string()));
+    if let Err(e) = history.save(run).await {
+        error!("failed to save run history: {}", e);
+    }
+    if mail.send(run_report(run)).await.is_err() {
+        error!("communication channel to notifier terminated");
+    }
+}
+
+/// Runs one sync, storing all files changed in OneDrive since the last run
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+async fn sync_run(mgr: &mut Mgr<'_>) -> Result<(), CloudSyncError> {
+    let start = Instant::now();
+    let started = mgr.run.started;
+    mgr.status.update(|s| {
+        s.state = SyncState::Running;
+        s.next_run = None;
+        s.progress = Some(Progress { started, file: 0, files: 0, bytes: 0, current: None });
+    });
+    check_tokens(mgr).await?;
+    check_token_expiry(mgr);
+
+    info!("get OneDrive deltas!");
+    let (files, other): (Vec<ItemInfo>, Vec<ItemInfo>) = mgr.one_drive.get_delta().await?.into_iter().partition(|f| f.file);
+    mgr.run.excluded = other.len() as u32;
+    if !files.is_empty() {
+        info!("checking objects!");
+        let count = files.len();
+        for (i, f) in files.into_iter().enumerate() {
+            let bytes = mgr.run.bytes;
+            mgr.status.update(|s| s.progress = Some(Progress { started, file: i + 1, files: count, bytes, current: Some(f.filename.clone()) }));
+            let path = mgr.manifest.canonical_path(&f.filename);
+            let created = key_time(mgr, &f);
+            let object_name = mgr.keys.object_key(&path, created);
+            if move_case_renamed(mgr, &f, &object_name).await? {
+                METRICS.files.with_label_values(&["renamed"]).inc();
+                mgr.run.renamed += 1;
+                mgr.run.change("renamed", &object_name, &f.filename, f.size);
+            } else if move_legacy_key(mgr, &f, &path, created, &object_name).await? {
+                METRICS.files.with_label_values(&["migrated"]).inc();
+            }
+            let stored = mgr.aws.get_object_info(&object_name).await?;
+            let mut blob = stored.as_ref().and_then(|t| t.metadata.get("blob").cloned());
+
+            // The checksum of a pointer object is that of its blob, as recorded when stored
+            let mut checksum = match &blob {
+                Some(_) => mgr.manifest.get(&object_name).and_then(|e| e.checksum.clone()),
+                None => stored.as_ref().and_then(|t| t.checksum.clone()),
+            };
+            if let Some(t) = &stored {
+                if backup_needed(f.size, t.size, f.mtime, t.mtime).await? {
+                    info!("updating file: {:?}", f.filename);
+                    (blob, checksum) = store_file(mgr, &f, &object_name).await?;
+                    METRICS.files.with_label_values(&["updated"]).inc();
+                    mgr.run.updated += 1;
+                    mgr.run.bytes += f.size;
+                    mgr.run.change("updated", &object_name, &f.filename, f.size);
                 } else {
-                    info!("adding file: {:?}", f.filename);
-                    backup_file(&mut mgr, &f.item_id, &f.filename, f.size, &f.content_type, f.mtime).await?;
-                    added += 1;
+                    mgr.run.skipped += 1;
                 }
-            }            
-        }
-        mgr.one_drive.save_delta_link().await?;
-        info!(target: "mail", "Done checking objects! Updates: {}, Adds: {}", updated, added);
-
-        sleep_until_time(&config.general.sync_time).await;
+            } else {
+                info!("adding file: {:?}", f.filename);
+                (blob, checksum) = store_file(mgr, &f, &object_name).await?;
+                METRICS.files.with_label_values(&["added"]).inc();
+                mgr.run.added += 1;
+                mgr.run.bytes += f.size;
+                mgr.run.change("added", &object_name, &f.filename, f.size);
+            }
+            mgr.manifest.insert(&object_name, ManifestEntry { path: f.filename.clone(), item_id: f.item_id.clone(), blob, checksum, created: Some(created) });
+        }            
     }
+    save_manifest(mgr).await?;
+    mgr.one_drive.save_delta_link().await?;
+
+    let run = &mgr.run;
+    info!("Done checking objects! Updates: {}, Adds: {}, Renames: {}", run.updated, run.added, run.renamed);
+
+    METRICS.runs.with_label_values(&["ok"]).inc();
+    METRICS.run_duration.observe(start.elapsed().as_secs_f64());
+    METRICS.last_success.set(Utc::now().timestamp());
+    let summary = RunSummary {
+        started,
+        finished: Utc::now(),
+        updated: run.updated,
+        added: run.added,
+        renamed: run.renamed,
+        bytes: run.bytes,
+        verified: run.verified,
+        verify_retried: run.verify_retried,
+        verify_failed: run.verify_failed,
+    };
+    mgr.status.update(|s| {
+        s.progress = None;
+        s.last_run = Some(summary);
+    });
+
+    Ok(())
 }
 
 /// Will sleep until next given time in local timezone
@@ -98,7 +300,8 @@ async fn sync_loop(config: &Config) -> Result<(), CloudSyncError> {
 /// # Arguments
 /// 
 /// * 'time' - the time to wake up in format %H:%M:%S (e.g. 00:01:00)
-async fn sleep_until_time(time: &str) {
+/// * 'status' - status shared with the web server
+async fn sleep_until_time(time: &str, status: &SyncStatus) {
     let now = Local::now();
     let mut proposed = Local::now().with_time(NaiveTime::parse_from_str(time, "%H:%M:%S").unwrap()).unwrap();
 
@@ -107,6 +310,13 @@ async fn sleep_until_time(time: &str) {
     }
 
     info!("sleeps until: {}", proposed);
+    status.update(|s| {
+        // A pause lasts until tokens are granted again
+        if !matches!(s.state, SyncState::Paused) {
+            s.state = SyncState::Sleeping;
+        }
+        s.next_run = Some(proposed);
+    });
     let duration_as_secs = (proposed - now).num_seconds() as u64;
     tokio::time::sleep_until(Instant::now() + Duration::from_secs(duration_as_secs)).await;
 }
@@ -119,16 +329,34 @@ async fn sleep_until_time(time: &str) {
 /// * 'mgr' - struct holding all managers and config
 async fn check_tokens(mgr: &mut Mgr<'_>) -> Result<(), CloudSyncError> {
     if mgr.tokens.is_expired() {
-        mgr.tokens.refresh_tokens(&mgr.config.onedrive).await?;
+        let refreshed = mgr.tokens.refresh_tokens(&mgr.config.onedrive).await;
+        METRICS.token_refreshes.with_label_values(&[result(refreshed.is_ok())]).inc();
+        refreshed?;
         mgr.one_drive.set_access_token(&mgr.tokens.get_access_token());
+        mgr.status.tokens(&mgr.tokens, &mgr.config.onedrive);
     }
 
     Ok(())
 }
 
+/// Warns if the refresh token is about to expire, so that access can be re-authorized before
+/// syncing stops
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+fn check_token_expiry(mgr: &Mgr<'_>) {
+    if let Some(expires_at) = mgr.tokens.expiry_warning(&mgr.config.onedrive) {
+        let days = (expires_at - Utc::now()).num_days().max(0);
+        warn!(target: "mail", "OneDrive authorization expires in about {} days ({}), visit {} to re-authorize",
+            days, expires_at.with_timezone(&Local).format("%Y-%m-%d %H:%M"), grant_url(&mgr.config.onedrive));
+    }
+}
+
 /// Returns true if there is a difference in a file between OneDrive and AWS
 /// It first tries to get the last modification time from AWS and if there is a difference it returns true. 
-/// If there wasn't any last modification time registered in AWS it checks if file sizes differs
+/// If there wasn't any last modification time registered in AWS it checks if file sizes differs, where
+/// the AWS size is the original file size for transformed (compressed or encrypted) objects
 /// 
 /// # Arguments
 /// 
@@ -141,87 +369,459 @@ async fn backup_needed(f_size: u64, t_size: Option<u64>, f_mtime: i64, t_mtime:
         if f_mtime != t_mtime {
             return Ok(true);
         }
-    } else if f_size != 0 && !t_size.is_some_and(|s| f_size == s) {
+    } else if f_size != 0 && t_size.is_none_or(|s| f_size != s) {
         return Ok(true);
     }
     
     Ok(false)
 }
 
+/// Returns the time used for the date placeholders of the key template, which must stay the
+/// same for a file as long as it exists: the OneDrive or file system creation time, or else the
+/// time recorded in the manifest when the file was first stored, or for a new file its mtime
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item representing the file
+fn key_time(mgr: &Mgr<'_>, item: &ItemInfo) -> i64 {
+    item.created
+        .or(item.fs_created)
+        .or_else(|| mgr.manifest.find_item(&item.item_id).and_then(|(_, e)| e.created))
+        .unwrap_or(item.mtime)
+}
+
+/// Moves the object of a file that has been renamed on OneDrive only by changing the case of
+/// its name, since that gives a new object key while the content is the same
+/// Only the name and path in the object metadata are updated, the mtime and size stay those of
+/// the moved content so that a file also changed since is still found to need a backup
+///
+/// It returns true if an object was moved
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item representing the file
+/// * 'object_name' - name and path to be used in the S3 bucket
+async fn move_case_renamed(mgr: &mut Mgr<'_>, item: &ItemInfo, object_name: &str) -> Result<bool, CloudSyncError> {
+    let old_name = match mgr.manifest.find_item(&item.item_id) {
+        Some((old_name, entry)) if old_name != object_name
+            && entry.path != item.filename
+            && entry.path.to_lowercase() == item.filename.to_lowercase() => old_name.clone(),
+        _ => return Ok(false),
+    };
+    if !move_object(mgr, item, &old_name, object_name).await? {
+        return Ok(false);
+    }
+    info!("moved renamed file: {:?}", item.filename);
+
+    Ok(true)
+}
+
+/// Moves the object of a file stored under a key from before paths were normalized and
+/// escaped in object keys, i.e. from the key recorded in the manifest for the same path, or
+/// else from the key the path would have been given then
+///
+/// It returns true if an object was moved
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item representing the file
+/// * 'path' - OneDrive path as used in the object key
+/// * 'created' - creation time used in the object key
+/// * 'object_name' - name and path to be used in the S3 bucket
+async fn move_legacy_key(mgr: &mut Mgr<'_>, item: &ItemInfo, path: &str, created: i64, object_name: &str) -> Result<bool, CloudSyncError> {
+    let old_name = match mgr.manifest.find_item(&item.item_id) {
+        Some((old_name, _)) if old_name == object_name => None,
+        Some((old_name, entry)) if entry.path == item.filename => Some(old_name.clone()),
+        _ => mgr.keys.legacy_key(path, created),
+    };
+    let old_name = match old_name {
+        Some(old_name) => old_name,
+        None => return Ok(false),
+    };
+    if !move_object(mgr, item, &old_name, object_name).await? {
+        return Ok(false);
+    }
+    info!("moved {} to normalized key {}", old_name, object_name);
+
+    Ok(true)
+}
+
+/// Moves an object to a new key, keeping its content, mtime and size and updating the name
+/// and path in its metadata
+///
+/// It returns true if the object was moved, or false if there is no object to move, the new
+/// key is already taken or copying failed
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item representing the file
+/// * 'old_name' - current name and path of the object
+/// * 'object_name' - new name and path of the object
+async fn move_object(mgr: &mut Mgr<'_>, item: &ItemInfo, old_name: &str, object_name: &str) -> Result<bool, CloudSyncError> {
+    let info = match mgr.aws.get_object_info(old_name).await? {
+        Some(info) => info,
+        None => return Ok(false),
+    };
+    if mgr.aws.get_object_info(object_name).await?.is_some() {
+        return Ok(false);
+    }
+
+    let mut renamed = item_metadata(mgr, item, object_name)?;
+    let mut metadata = info.metadata;
+    for key in NAME_METADATA {
+        metadata.remove(key);
+        if let Some(value) = renamed.remove(key) {
+            metadata.insert(key.to_string(), value);
+        }
+    }
+    fit_metadata(&mut metadata);
+
+    if let Err(e) = mgr.aws.copy_object(&mgr.aws, old_name, object_name, Some(&metadata)).await {
+        warn!("failed to move {} to {}: {}", old_name, object_name, e);
+        return Ok(false);
+    }
+    mgr.aws.delete_object(old_name).await?;
+    if let Some(entry) = mgr.manifest.get(old_name).cloned() {
+        mgr.manifest.remove(old_name);
+        mgr.manifest.insert(object_name, entry);
+    }
+
+    Ok(true)
+}
+
+/// Stores a file in the bucket
+/// With deduplication enabled, and a SHA-256 or SHA-1 content hash given by OneDrive, the content
+/// is stored once as a content addressed blob and the object is a pointer (without content) to
+/// that blob. An existing blob is only reused if its size matches the file and the checksum S3
+/// reports for it is the one recorded in the manifest when it was stored, otherwise it is stored
+/// again. Without a cryptographic hash the content is stored in the object itself
+///
+/// It returns the blob object name if the object is a pointer, and the checksum of the stored
+/// content as recorded by S3
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item representing the file to store
+/// * 'object_name' - name and path to be used in the S3 bucket
+async fn store_file(mgr: &mut Mgr<'_>, item: &ItemInfo, object_name: &str) -> Result<(Option<String>, Option<String>), CloudSyncError> {
+    let mut metadata = item_metadata(mgr, item, object_name)?;
+
+    match (dedup_hash(item), mgr.config.aws.deduplicate) {
+        (Some(content_hash), true) => {
+            let blob_name = mgr.keys.internal_key(&format!("{}{}-{}", BLOB_PREFIX, content_hash, item.size));
+            let stored = mgr.aws.get_object_info(&blob_name).await?;
+            let recorded = mgr.manifest.blob_checksum(&blob_name);
+            let checksum = match stored {
+                Some(info) if info.size == Some(item.size) && recorded == Some(&info.checksum) => {
+                    info!("content already stored for: {:?}", item.filename);
+                    info.checksum
+                },
+                stored => {
+                    if stored.is_some() {
+                        warn!("stored blob {} doesn't match {:?}, storing it again", blob_name, item.filename);
+                    }
+                    let mut blob_metadata = HashMap::from([("size".to_string(), item.size.to_string())]);
+                    if !mgr.keys.is_obfuscated() {
+                        blob_metadata.insert("content-hash".to_string(), content_hash.to_string());
+                    }
+                    backup_file(mgr, item, &blob_name, &blob_metadata).await?
+                },
+            };
+
+            metadata.insert("blob".to_string(), blob_name.clone());
+            fit_metadata(&mut metadata);
+            mgr.aws.put_object(object_name, &item.content_type, &metadata, &None, Vec::new()).await?;
+
+            Ok((Some(blob_name), checksum))
+        },
+        _ => {
+            let checksum = backup_file(mgr, item, object_name, &metadata).await?;
+            Ok((None, checksum))
+        }
+    }
+}
+
+/// Returns the content hash of a file if it is strong enough to deduplicate on, i.e. SHA-256 or
+/// SHA-1. QuickXorHash isn't a cryptographic hash, so files with only that are never deduplicated
+///
+/// # Arguments
+///
+/// * 'item' - OneDrive item representing the file
+fn dedup_hash(item: &ItemInfo) -> Option<&str> {
+    item.content_hash.as_deref()
+        .filter(|h| h.starts_with("sha256-") || h.starts_with("sha1-"))
+}
+
 /// Backs up or sync a file from OneDrive to AWS
+/// If upload verification is enabled the stored object is checked after each upload, and the
+/// upload retried up to the configured number of attempts if it doesn't match. If it still doesn't
+/// match the object is deleted and an error returned, failing the run so that the file is uploaded
+/// again when it is retried
+///
+/// It returns the checksum of the object as recorded by S3
 ///
 /// # Arguments
 ///
 /// * 'mgr' - struct holding all managers and config
-/// * 'item_id' - OneDrive item id representing the file to copy
-/// * 'filename' - filename and path
-/// * 'size' - size of the file on OneDrive
-/// * 'content_type' - the file Content-Type
-/// * 'mtime' - last modification datetime as a timestamp 
-async fn backup_file(mgr: &mut Mgr<'_>, item_id: &str, filename: &str, size: u64, content_type: &Option<String>, mtime: i64) -> Result<(), CloudSyncError> {
-    if size > AWS::get_chunk_size() {
-        upload_file(mgr, item_id, filename, size, content_type, mtime).await?;
-    } else {
-        copy_file(mgr, item_id, filename, size, content_type, mtime).await?
+/// * 'item' - OneDrive item representing the file to copy
+/// * 'object_name' - name and path to be used in the S3 bucket
+/// * 'metadata' - user metadata to store with the object
+async fn backup_file(mgr: &mut Mgr<'_>, item: &ItemInfo, object_name: &str, metadata: &HashMap<String, String>) -> Result<Option<String>, CloudSyncError> {
+    let attempts = mgr.config.aws.verify_attempts.unwrap_or(DEFAULT_VERIFY_ATTEMPTS).max(1);
+    let mut attempt = 1;
+    loop {
+        let upload = if item.size > mgr.aws.multipart_threshold() {
+            upload_file(mgr, item, object_name, metadata).await?
+        } else {
+            copy_file(mgr, item, object_name, metadata).await?
+        };
+
+        if !mgr.config.aws.verify_uploads {
+            return Ok(upload.checksum);
+        }
+        match verify_upload(mgr, object_name, &upload).await? {
+            None => {
+                mgr.run.verified += 1;
+                return Ok(upload.checksum);
+            },
+            Some(mismatch) if attempt < attempts => {
+                warn!("verification of {:?} failed ({}), uploading again", item.filename, mismatch);
+                mgr.run.verify_retried += 1;
+                METRICS.retries.with_label_values(&["verify"]).inc();
+                attempt += 1;
+            },
+            Some(mismatch) => {
+                mgr.run.failures.push(format!("{}: verification failed: {}", item.filename, mismatch));
+                mgr.run.verify_failed += 1;
+
+                // Left in place, an object with matching mtime and size would be skipped by the retried run
+                if let Err(e) = mgr.aws.delete_object(object_name).await {
+                    warn!("failed to delete unverified object {}: {}", object_name, e);
+                }
+                return Err(CloudSyncError::Verification(format!("{} failed after {} attempts: {}", item.filename, attempts, mismatch)));
+            },
+        }
     }
-    
-    Ok(())
+}
+
+/// Checks that an uploaded object is stored with the expected length, metadata and checksum
+/// The metadata is compared with exactly what was uploaded, since e.g. blobs carry other
+/// metadata than the objects of files
+///
+/// It returns a description of the first mismatch found, or None if the object is as expected
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'object_name' - name and path used in the S3 bucket
+/// * 'upload' - what was stored by the upload
+async fn verify_upload(mgr: &Mgr<'_>, object_name: &str, upload: &Upload) -> Result<Option<String>, CloudSyncError> {
+    let info = match mgr.aws.get_object_info(object_name).await? {
+        Some(info) => info,
+        None => return Ok(Some("object not found".to_string())),
+    };
+
+    let metadata = upload.metadata.iter()
+        .find(|(key, value)| info.metadata.get(*key) != Some(*value));
+    let mismatch = if info.content_length != Some(upload.length) {
+        Some(format!("content length {:?}, expected {}", info.content_length, upload.length))
+    } else if let Some((key, value)) = metadata {
+        Some(format!("{} {:?}, expected {}", key, info.metadata.get(key), value))
+    } else if upload.checksum.is_some() && info.checksum != upload.checksum {
+        Some(format!("checksum {:?}, expected {:?}", info.checksum, upload.checksum))
+    } else {
+        None
+    };
+
+    Ok(mismatch)
 }
 
 /// Copies one file from OneDrive to AWS S3
-/// Use this function for files less or equal to 10MB since it is reading and writing the
-/// entire file in one go
+/// Use this function for files up to the multipart threshold since it is reading and writing
+/// the entire file in one go
 /// 
 /// # Arguments
 ///
 /// * 'mgr' - struct holding all managers and config
-/// * 'item_id' - OneDrive item id representing the file to copy
-/// * 'filename' - filename and path
-/// * 'size' - size of the file on OneDrive
-/// * 'content_type' - the file Content-Type
-/// * 'mtime' - last modification datetime as a timestamp 
-async fn copy_file(mgr: &mut Mgr<'_>, item_id: &str, filename: &str, size: u64, content_type: &Option<String>, mtime: i64) -> Result<(), CloudSyncError> {
+/// * 'item' - OneDrive item representing the file to copy
+/// * 'object_name' - name and path to be used in the S3 bucket
+/// * 'metadata' - user metadata to store with the object
+async fn copy_file(mgr: &mut Mgr<'_>, item: &ItemInfo, object_name: &str, metadata: &HashMap<String, String>) -> Result<Upload, CloudSyncError> {
     check_tokens(mgr).await?;
     
-    let download_url = mgr.one_drive.get_download_url(item_id).await?;
+    let download_url = mgr.one_drive.get_download_url(&item.item_id).await?;
     let content = mgr.one_drive.get_file(&download_url).await?;
-    if content.len() != size as usize {
+    if content.len() != item.size as usize {
         return Err(CloudSyncError::OneDrive("download size mismatch".to_string()));
     };
-        
-    mgr.aws.put_object(filename, &content_type, mtime, content).await?;
+
+    let compressor = mgr.compression.compressor(&item.filename, &item.content_type)?;
+    let mut transform = UploadTransform::new(&mgr.master_key, compressor)?;
+    let mut content = transform.update(content)?;
+    content.extend(transform.finish()?);
+
+    let metadata = with_transform_metadata(metadata, &transform);
+    let storage_class = mgr.aws.storage_class(&item.filename, item.size);
+    let length = content.len() as u64;
+    let checksum = mgr.aws.put_object(object_name, &item.content_type, &metadata, &storage_class, content).await?;
     
-    Ok(())
+    Ok(Upload { checksum, length, metadata })
 }
 
 /// Uploads one file from OneDrive to AWS S3
-/// Use this function for files bigger than 10MB since it is reading and writing the
-/// file in chunks of 10MB
+/// Use this function for files bigger than the multipart threshold since it is reading and
+/// writing the file in parts, of a size planned for the file
 ///
 /// # Arguments
 ///
 /// * 'mgr' - struct holding all managers and config
-/// * 'item_id' - OneDrive item id representing the file to copy
-/// * 'filename' - filename and path
-/// * 'size' - size of the file on OneDrive
-/// * 'content_type' - the file Content-Type
-/// * 'mtime' - last modification datetime as a timestamp 
-async fn upload_file(mgr: &mut Mgr<'_>, item_id: &str, filename: &str, size: u64, content_type: &Option<String>, mtime: i64) -> Result<(), CloudSyncError> {
-    AWS::check_for_multipart_upload(size)?;
-    let chunk_size = AWS::get_chunk_size();
-
-    let (mut url, mut create_url_time) = get_check_download_url(mgr, item_id, None).await?;
-    let (mut upload_parts, upload_id) = mgr.aws.create_multipart_upload(filename, &content_type, mtime).await?;
-    
-    let chunk = Chunk::new(size, chunk_size);
-    for (part, from, to) in chunk {
-        (url, create_url_time) = get_check_download_url(mgr, item_id, Some((url, create_url_time))).await?;
+/// * 'item' - OneDrive item representing the file to copy
+/// * 'object_name' - name and path to be used in the S3 bucket
+/// * 'metadata' - user metadata to store with the object
+async fn upload_file(mgr: &mut Mgr<'_>, item: &ItemInfo, object_name: &str, metadata: &HashMap<String, String>) -> Result<Upload, CloudSyncError> {
+    let plan = mgr.aws.part_plan(item.size)?;
+    let part_size = plan.part_size as usize;
+    info!("uploading {:?} planned as {} parts of {} bytes", item.filename, plan.part_count, plan.part_size);
+
+    let (mut url, mut create_url_time) = get_check_download_url(mgr, &item.item_id, None).await?;
+    let compressor = mgr.compression.compressor(&item.filename, &item.content_type)?;
+    let mut transform = UploadTransform::new(&mgr.master_key, compressor)?;
+    let metadata = with_transform_metadata(metadata, &transform);
+    let storage_class = mgr.aws.storage_class(&item.filename, item.size);
+    let (mut upload_parts, upload_id) = mgr.aws.create_multipart_upload(object_name, &item.content_type, &metadata, &storage_class).await?;
+
+    // Transformed output doesn't follow the source chunk boundaries, so it is collected
+    // and uploaded in parts of the planned size whenever available
+    let mut part_number = 1;
+    let mut length = 0;
+    let mut buffer: Vec<u8> = Vec::new();
+    let chunk = Chunk::new(&plan);
+    for (_, from, to) in chunk {
+        (url, create_url_time) = get_check_download_url(mgr, &item.item_id, Some((url, create_url_time))).await?;
         
         let bytes = mgr.one_drive.get_file_range(&url, from, to).await?;
-        mgr.aws.upload_part(filename, &upload_id, part, bytes, &mut upload_parts).await?;
+        buffer.extend(transform.update(bytes)?);
+        while buffer.len() >= part_size {
+            length += part_size as u64;
+            let rest = buffer.split_off(part_size);
+            mgr.aws.upload_part(object_name, &upload_id, part_number, std::mem::replace(&mut buffer, rest), &mut upload_parts).await?;
+            part_number += 1;
+        }
+    }
+    buffer.extend(transform.finish()?);
+    if buffer.len() > part_size {
+        length += part_size as u64;
+        let rest = buffer.split_off(part_size);
+        mgr.aws.upload_part(object_name, &upload_id, part_number, std::mem::replace(&mut buffer, rest), &mut upload_parts).await?;
+        part_number += 1;
     }
-    mgr.aws.complete_multipart_upload(filename, &upload_id, upload_parts).await?;
+    if !buffer.is_empty() || upload_parts.is_empty() {
+        length += buffer.len() as u64;
+        mgr.aws.upload_part(object_name, &upload_id, part_number, buffer, &mut upload_parts).await?;
+    }
+    let checksum = mgr.aws.complete_multipart_upload(object_name, &upload_id, upload_parts).await?;
     
+    Ok(Upload { checksum, length, metadata })
+}
+
+/// Returns the user metadata to store with an object for a OneDrive item
+/// Timestamps are stored as unix timestamps and the original name percent-encoded, since
+/// metadata must be valid HTTP header values
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+/// * 'item' - OneDrive item the object is created from
+/// * 'object_name' - name and path used in the S3 bucket
+fn item_metadata(mgr: &Mgr<'_>, item: &ItemInfo, object_name: &str) -> Result<HashMap<String, String>, CloudSyncError> {
+    let mut metadata: HashMap<String, String> = HashMap::new();
+    metadata.insert("mtime".to_string(), item.mtime.to_string());
+    metadata.insert("size".to_string(), item.size.to_string());
+    metadata.insert("onedrive-id".to_string(), item.item_id.clone());
+    if let Some(parent_id) = &item.parent_id {
+        metadata.insert("parent-id".to_string(), parent_id.clone());
+    }
+    if let Some(created) = item.created {
+        metadata.insert("created".to_string(), created.to_string());
+    }
+    if let Some(fs_created) = item.fs_created {
+        metadata.insert("fs-created".to_string(), fs_created.to_string());
+    }
+    if let Some(fs_modified) = item.fs_modified {
+        metadata.insert("fs-modified".to_string(), fs_modified.to_string());
+    }
+
+    // Names and content hashes would reveal what obfuscated keys are meant to hide, the
+    // real path is then only stored sealed
+    if let Some(sealed_path) = mgr.keys.sealed_path(&item.filename)? {
+        metadata.insert("cse-path".to_string(), sealed_path);
+    } else {
+        metadata.insert("name".to_string(), utf8_percent_encode(&item.name, METADATA_ENCODE_SET).to_string());
+        if let Some(content_hash) = &item.content_hash {
+            metadata.insert("content-hash".to_string(), content_hash.clone());
+        }
+
+        // The real path can't be told from the object key if it was normalized, escaped,
+        // hashed or laid out by a key template, very long paths are left to the manifest
+        let path = utf8_percent_encode(&item.filename, METADATA_ENCODE_SET).to_string();
+        if object_name != item.filename && path.len() <= MAX_PATH_METADATA {
+            metadata.insert("path".to_string(), path);
+        }
+    }
+
+    Ok(metadata)
+}
+
+/// Returns user metadata extended with the metadata needed to reverse an upload transform
+///
+/// # Arguments
+///
+/// * 'metadata' - user metadata
+/// * 'transform' - the upload transform used for the object content
+fn with_transform_metadata(metadata: &HashMap<String, String>, transform: &UploadTransform) -> HashMap<String, String> {
+    let mut metadata = metadata.clone();
+    metadata.extend(transform.metadata().clone());
+    fit_metadata(&mut metadata);
+
+    metadata
+}
+
+/// Drops names and paths from user metadata until it fits in the size S3 allows
+///
+/// # Arguments
+///
+/// * 'metadata' - user metadata
+fn fit_metadata(metadata: &mut HashMap<String, String>) {
+    let size = |metadata: &HashMap<String, String>| metadata.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+    for key in NAME_METADATA {
+        if size(metadata) <= MAX_USER_METADATA {
+            return;
+        }
+        if metadata.remove(key).is_some() {
+            info!("dropped {} from object metadata exceeding {} bytes", key, MAX_USER_METADATA);
+        }
+    }
+}
+
+/// Saves the manifest to file, and when object keys are not the plain OneDrive paths also a
+/// copy (encrypted if client side encryption is enabled) to the bucket so that real paths can
+/// be recovered even if the local file is lost
+///
+/// # Arguments
+///
+/// * 'mgr' - struct holding all managers and config
+async fn save_manifest(mgr: &Mgr<'_>) -> Result<(), CloudSyncError> {
+    mgr.manifest.save(&mgr.config.manifest_path()).await?;
+
+    if !mgr.keys.is_plain() {
+        mgr.manifest.upload(&mgr.aws, &mgr.master_key).await?;
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,314 @@
+use std::io::Write;
+use std::path::Path;
+use flate2::write::{GzEncoder, MultiGzDecoder};
+use zstd::stream::raw::Operation;
+use crate::errors::TransformError;
+use crate::initialization::Compression;
+
+/// Supported compression codecs
+///
+#[derive(Clone, Copy)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+
+    /// Returns the codec for a name as given in configuration or object metadata
+    ///
+    /// # Arguments
+    ///
+    /// * 'name' - name of the codec
+    pub fn from_name(name: &str) -> Result<Self, TransformError> {
+        match name {
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            other => Err(TransformError(format!("unknown compression codec: {}", other))),
+        }
+    }
+
+    /// Returns the name of the codec
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        }
+    }
+}
+
+/// Decides which files to compress, based on MIME type or file extension
+///
+pub struct CompressionRules {
+    codec: Option<Codec>,
+    level: Option<i32>,
+    mime_types: Vec<String>,
+    extensions: Vec<String>,
+}
+
+impl CompressionRules {
+
+    /// Creates compression rules from configuration
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - compression configuration parameters
+    pub fn new(config: &Compression) -> Result<Self, TransformError> {
+        let codec = match &config.codec {
+            Some(name) => Some(Codec::from_name(name)?),
+            None => None,
+        };
+
+        Ok(CompressionRules {
+            codec,
+            level: config.level,
+            mime_types: config.mime_types.iter().map(|m| m.to_lowercase()).collect(),
+            extensions: config.extensions.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect(),
+        })
+    }
+
+    /// Returns a compressor if the file shall be compressed, otherwise None
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path of the file
+    /// * 'content_type' - the file Content-Type
+    pub fn compressor(&self, path: &str, content_type: &Option<String>) -> Result<Option<Compressor>, TransformError> {
+        let codec = match self.codec {
+            Some(codec) => codec,
+            None => return Ok(None),
+        };
+
+        let mime_match = content_type.as_ref().is_some_and(|c| {
+            let c = c.to_lowercase();
+            self.mime_types.iter().any(|m| match m.strip_suffix('*') {
+                Some(prefix) => c.starts_with(prefix),
+                None => c == *m,
+            })
+        });
+        let extension_match = Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| self.extensions.contains(&e.to_lowercase()));
+
+        if mime_match || extension_match {
+            Ok(Some(Compressor::new(codec, self.level)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+/// Streaming compressor for one file
+///
+pub struct Compressor {
+    codec: Codec,
+    encoder: Option<Encoder>,
+}
+
+impl Compressor {
+
+    /// Creates a new compressor
+    ///
+    /// # Arguments
+    ///
+    /// * 'codec' - compression codec
+    /// * 'level' - compression level, codec default if None
+    fn new(codec: Codec, level: Option<i32>) -> Result<Self, TransformError> {
+        let encoder = match codec {
+            Codec::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?),
+            Codec::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::new(level.unwrap_or(6).clamp(0, 9) as u32))),
+        };
+
+        Ok(Compressor { codec, encoder: Some(encoder) })
+    }
+
+    /// Returns the codec used
+    ///
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Compresses data and returns whatever compressed output is available
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - uncompressed data
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, TransformError> {
+        match self.encoder.as_mut() {
+            Some(Encoder::Zstd(e)) => { e.write_all(data)?; Ok(std::mem::take(e.get_mut())) },
+            Some(Encoder::Gzip(e)) => { e.write_all(data)?; Ok(std::mem::take(e.get_mut())) },
+            None => Err(TransformError("compressor already finished".to_string())),
+        }
+    }
+
+    /// Ends the compressed stream and returns the remaining output
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, TransformError> {
+        match self.encoder.take() {
+            Some(Encoder::Zstd(e)) => Ok(e.finish()?),
+            Some(Encoder::Gzip(e)) => Ok(e.finish()?),
+            None => Err(TransformError("compressor already finished".to_string())),
+        }
+    }
+}
+
+/// Size of the buffer zstd output is decompressed into
+const ZSTD_OUTPUT_SIZE: usize = 128 * 1024;
+
+/// Streaming zstd decoder that keeps track of whether the last frame was complete
+///
+struct ZstdDecoder {
+    decoder: zstd::stream::raw::Decoder<'static>,
+    // Input size hint from the last step, zero when a frame has just ended
+    hint: usize,
+}
+
+impl ZstdDecoder {
+
+    /// Decompresses data and returns whatever decompressed output is available
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - compressed data
+    fn update(&mut self, mut data: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; ZSTD_OUTPUT_SIZE];
+        loop {
+            let status = self.decoder.run_on_buffers(data, &mut buf)?;
+
+            // A step without progress after a frame has ended would ask for the next frame
+            if status.bytes_read > 0 || status.bytes_written > 0 {
+                self.hint = status.remaining;
+            }
+            out.extend_from_slice(&buf[..status.bytes_written]);
+            data = &data[status.bytes_read..];
+
+            // Output filling the buffer may leave more output buffered in the decoder
+            if data.is_empty() && status.bytes_written < buf.len() {
+                return Ok(out);
+            }
+        }
+    }
+
+    /// Returns the remaining output, or an error if the stream ended within a frame
+    ///
+    fn finish(&mut self) -> Result<Vec<u8>, TransformError> {
+        let out = self.update(&[])?;
+        if self.hint != 0 {
+            return Err(TransformError("truncated zstd stream".to_string()));
+        }
+
+        Ok(out)
+    }
+}
+
+enum Decoder {
+    Zstd(ZstdDecoder),
+    Gzip(MultiGzDecoder<Vec<u8>>),
+}
+
+/// Streaming decompressor for one object
+///
+pub struct Decompressor {
+    decoder: Option<Decoder>,
+}
+
+impl Decompressor {
+
+    /// Creates a new decompressor
+    ///
+    /// # Arguments
+    ///
+    /// * 'codec' - compression codec
+    pub fn new(codec: Codec) -> Result<Self, TransformError> {
+        let decoder = match codec {
+            Codec::Zstd => Decoder::Zstd(ZstdDecoder { decoder: zstd::stream::raw::Decoder::new()?, hint: 1 }),
+            Codec::Gzip => Decoder::Gzip(MultiGzDecoder::new(Vec::new())),
+        };
+
+        Ok(Decompressor { decoder: Some(decoder) })
+    }
+
+    /// Decompresses data and returns whatever decompressed output is available
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - compressed data
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, TransformError> {
+        match self.decoder.as_mut() {
+            Some(Decoder::Zstd(d)) => d.update(data),
+            Some(Decoder::Gzip(d)) => { d.write_all(data)?; Ok(std::mem::take(d.get_mut())) },
+            None => Err(TransformError("decompressor already finished".to_string())),
+        }
+    }
+
+    /// Ends decompression and returns the remaining output, or an error if the compressed
+    /// stream is incomplete
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, TransformError> {
+        match self.decoder.take() {
+            Some(Decoder::Zstd(mut d)) => d.finish(),
+            Some(Decoder::Gzip(d)) => Ok(d.finish()?),
+            None => Err(TransformError("decompressor already finished".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plaintext() -> Vec<u8> {
+        (0..100_000u32).flat_map(|i| format!("line {} of {}\n", i % 977, i / 977).into_bytes()).collect()
+    }
+
+    fn compress(codec: Codec, data: &[u8]) -> Vec<u8> {
+        let mut compressor = Compressor::new(codec, None).unwrap();
+        let mut out = Vec::new();
+        for chunk in data.chunks(70_000) {
+            out.extend(compressor.update(chunk).unwrap());
+        }
+        out.extend(compressor.finish().unwrap());
+
+        out
+    }
+
+    fn decompress(codec: Codec, data: &[u8]) -> Result<Vec<u8>, TransformError> {
+        let mut decompressor = Decompressor::new(codec)?;
+        let mut out = Vec::new();
+        for chunk in data.chunks(9_999) {
+            out.extend(decompressor.update(chunk)?);
+        }
+        out.extend(decompressor.finish()?);
+
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = plaintext();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compressed = compress(codec, &data);
+            assert!(compressed.len() < data.len() / 4);
+            assert_eq!(decompress(codec, &compressed).unwrap(), data);
+            assert_eq!(decompress(codec, &compress(codec, b"")).unwrap(), b"");
+        }
+    }
+
+    #[test]
+    fn truncated_input_rejected() {
+        let data = plaintext();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compressed = compress(codec, &data);
+            for len in [0, 1, compressed.len() / 2, compressed.len() - 1] {
+                assert!(decompress(codec, &compressed[..len]).is_err(), "{} truncated to {}", codec.name(), len);
+            }
+        }
+    }
+}
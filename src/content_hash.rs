@@ -0,0 +1,165 @@
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Width of a QuickXorHash in bytes
+const QUICK_XOR_SIZE: usize = 20;
+
+/// Number of bits each byte is shifted relative to the previous one in a QuickXorHash
+const QUICK_XOR_SHIFT: usize = 11;
+
+/// Computes a content hash in the same form as OneDrive::content_hash, i.e. as
+/// <algorithm>-<lowercase hex>, so that content can be compared with the hash OneDrive gives
+///
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    QuickXor(QuickXorHash),
+}
+
+impl ContentHasher {
+
+    /// Returns a hasher of the same algorithm as the given content hash, or None if the
+    /// algorithm isn't known
+    ///
+    /// # Arguments
+    ///
+    /// * 'content_hash' - a content hash as given by OneDrive::content_hash
+    pub fn for_hash(content_hash: &str) -> Option<Self> {
+        match content_hash.split_once('-')?.0 {
+            "sha256" => Some(ContentHasher::Sha256(Sha256::new())),
+            "sha1" => Some(ContentHasher::Sha1(Sha1::new())),
+            "qxh" => Some(ContentHasher::QuickXor(QuickXorHash::default())),
+            _ => None,
+        }
+    }
+
+    /// Adds data to the hash
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - the data to add
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Sha256(h) => h.update(data),
+            ContentHasher::Sha1(h) => h.update(data),
+            ContentHasher::QuickXor(h) => h.update(data),
+        }
+    }
+
+    /// Returns the content hash of all data added
+    ///
+    pub fn finish(self) -> String {
+        let (algorithm, hash) = match self {
+            ContentHasher::Sha256(h) => ("sha256", h.finalize().to_vec()),
+            ContentHasher::Sha1(h) => ("sha1", h.finalize().to_vec()),
+            ContentHasher::QuickXor(h) => ("qxh", h.finish().to_vec()),
+        };
+
+        format!("{}-{}", algorithm, hash.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    }
+}
+
+/// Microsoft's QuickXorHash as used by OneDrive for Business (and personal OneDrive where
+/// no SHA hash is given)
+/// Each byte is XORed into a 160 bit register at a position advancing 11 bits per byte,
+/// wrapping around, and the total length is finally XORed into the last 64 bits
+///
+#[derive(Default)]
+pub struct QuickXorHash {
+    // One byte more than the hash width to take bits shifted past the end before wrapping
+    cells: [u8; QUICK_XOR_SIZE + 1],
+    shift: usize,
+    length: u64,
+}
+
+impl QuickXorHash {
+
+    /// Adds data to the hash
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - the data to add
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            let shifted = (b as u16) << (self.shift % 8);
+            let index = self.shift / 8;
+            self.cells[index] ^= shifted as u8;
+            self.cells[index + 1] ^= (shifted >> 8) as u8;
+            self.shift = (self.shift + QUICK_XOR_SHIFT) % (QUICK_XOR_SIZE * 8);
+        }
+        self.length += data.len() as u64;
+    }
+
+    /// Returns the hash of all data added
+    ///
+    pub fn finish(mut self) -> [u8; QUICK_XOR_SIZE] {
+        self.cells[0] ^= self.cells[QUICK_XOR_SIZE];
+
+        let mut hash = [0u8; QUICK_XOR_SIZE];
+        hash.copy_from_slice(&self.cells[..QUICK_XOR_SIZE]);
+        for (i, b) in self.length.to_le_bytes().iter().enumerate() {
+            hash[QUICK_XOR_SIZE - 8 + i] ^= b;
+        }
+
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use super::*;
+
+    fn quick_xor(data: &[u8]) -> String {
+        let mut hash = QuickXorHash::default();
+        hash.update(data);
+
+        BASE64.encode(hash.finish())
+    }
+
+    fn long_input() -> Vec<u8> {
+        (0..1000).map(|i| ((i * 7 + 3) % 256) as u8).collect()
+    }
+
+    #[test]
+    fn quick_xor_empty() {
+        assert_eq!(quick_xor(b""), "AAAAAAAAAAAAAAAAAAAAAAAAAAA=");
+    }
+
+    #[test]
+    fn quick_xor_short() {
+        assert_eq!(quick_xor(b"J"), "SgAAAAAAAAAAAAAAAQAAAAAAAAA=");
+        assert_eq!(quick_xor(b"hello world"), "aCgDG9jwBhDc4Q1yawMZAAAAAAA=");
+    }
+
+    #[test]
+    fn quick_xor_wraps() {
+        // Byte 14 is shifted across the end of the 160 bit register, byte 15 onwards wrap around
+        assert_eq!(quick_xor(b"The quick brown fox jumps over the lazy dog"), "bMSlbysmxJL6S75XwfMcQZOpcr4=");
+        assert_eq!(quick_xor(&long_input()), "dgD8j0n8sM0aPE5CUJ8tqmilX/E=");
+    }
+
+    #[test]
+    fn quick_xor_chunked() {
+        let data = long_input();
+        for size in [1, 3, 19, 20, 160, 333] {
+            let mut hash = QuickXorHash::default();
+            data.chunks(size).for_each(|chunk| hash.update(chunk));
+            assert_eq!(BASE64.encode(hash.finish()), quick_xor(&data));
+        }
+    }
+
+    #[test]
+    fn content_hasher_format() {
+        let mut hasher = ContentHasher::for_hash("qxh-0000").unwrap();
+        hasher.update(b"hello world");
+        assert_eq!(hasher.finish(), "qxh-6828031bd8f00610dce10d726b03190000000000");
+
+        let mut hasher = ContentHasher::for_hash("sha1-0000").unwrap();
+        hasher.update(b"hello world");
+        assert_eq!(hasher.finish(), "sha1-2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+
+        assert!(ContentHasher::for_hash("md5-0000").is_none());
+    }
+}
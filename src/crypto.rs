@@ -0,0 +1,386 @@
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::aead::rand_core::RngCore;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::errors::CryptoError;
+
+/// Identifies the client side encryption format, stored as object metadata
+pub const ALGORITHM: &str = "AES256GCM-STREAM64K-V1";
+
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const PREFIX_SIZE: usize = 7;
+const KEY_SIZE: usize = 32;
+const WRAP_NONCE_SIZE: usize = 12;
+const WRAP_AAD: &[u8] = b"cloud_sync data key";
+const NAME_KEY_INFO: &[u8] = b"cloud_sync object key";
+const NAME_AAD: &[u8] = b"cloud_sync object name";
+
+/// Master key used for wrapping the per-file data keys
+///
+pub struct MasterKey {
+    cipher: Aes256Gcm,
+    key: Vec<u8>,
+}
+
+impl MasterKey {
+
+    /// Creates a master key from its base64 representation
+    ///
+    /// # Arguments
+    ///
+    /// * 'key' - base64 encoded 256-bit key
+    pub fn new(key: &str) -> Result<Self, CryptoError> {
+        let key = BASE64.decode(key)?;
+        if key.len() != KEY_SIZE {
+            return Err(CryptoError("master key must be 256 bits".to_string()));
+        }
+
+        Ok(MasterKey { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)), key })
+    }
+
+    /// Returns a name key derived from the master key, used for obfuscating object keys
+    ///
+    pub fn name_key(&self) -> NameKey {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .expect("hmac accepts any key length");
+        mac.update(NAME_KEY_INFO);
+
+        NameKey { hmac_key: mac.finalize().into_bytes().to_vec(), cipher: self.cipher.clone() }
+    }
+
+    /// Creates an encryptor with a new random data key
+    ///
+    /// It returns a tuple of the encryptor and the data key wrapped by the master key,
+    /// base64 encoded to be stored in object metadata
+    ///
+    pub fn encryptor(&self) -> Result<(Encryptor, String), CryptoError> {
+        let mut secret = [0u8; KEY_SIZE + PREFIX_SIZE];
+        OsRng.fill_bytes(&mut secret);
+
+        let mut wrap_nonce = [0u8; WRAP_NONCE_SIZE];
+        OsRng.fill_bytes(&mut wrap_nonce);
+
+        let mut wrapped = wrap_nonce.to_vec();
+        wrapped.extend(self.cipher.encrypt(Nonce::from_slice(&wrap_nonce), Payload { msg: &secret, aad: WRAP_AAD })?);
+
+        let encryptor = Encryptor { stream: Stream::new(&secret), buf: Vec::new() };
+
+        Ok((encryptor, BASE64.encode(wrapped)))
+    }
+
+    /// Creates a decryptor by unwrapping a data key as stored in object metadata
+    ///
+    /// # Arguments
+    ///
+    /// * 'wrapped' - base64 encoded wrapped data key
+    pub fn decryptor(&self, wrapped: &str) -> Result<Decryptor, CryptoError> {
+        let wrapped = BASE64.decode(wrapped)?;
+        if wrapped.len() <= WRAP_NONCE_SIZE {
+            return Err(CryptoError("wrapped data key too short".to_string()));
+        }
+
+        let (wrap_nonce, wrapped) = wrapped.split_at(WRAP_NONCE_SIZE);
+        let secret = self.cipher.decrypt(Nonce::from_slice(wrap_nonce), Payload { msg: wrapped, aad: WRAP_AAD })?;
+        if secret.len() != KEY_SIZE + PREFIX_SIZE {
+            return Err(CryptoError("invalid data key length".to_string()));
+        }
+
+        Ok(Decryptor { stream: Stream::new(&secret), buf: Vec::new() })
+    }
+}
+
+/// Key for deterministic obfuscation of object keys and for sealing the real names
+/// stored in object metadata
+///
+pub struct NameKey {
+    hmac_key: Vec<u8>,
+    cipher: Aes256Gcm,
+}
+
+impl NameKey {
+
+    /// Returns a deterministic obfuscated object key as a hex encoded HMAC-SHA256
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - the real object key
+    pub fn object_key(&self, path: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.hmac_key)
+            .expect("hmac accepts any key length");
+        mac.update(path.as_bytes());
+
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Encrypts a name and returns it base64 encoded
+    ///
+    /// # Arguments
+    ///
+    /// * 'name' - the name to seal
+    pub fn seal(&self, name: &str) -> Result<String, CryptoError> {
+        let mut nonce = [0u8; WRAP_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: name.as_bytes(), aad: NAME_AAD })?);
+
+        Ok(BASE64.encode(sealed))
+    }
+
+    /// Decrypts a name sealed by the seal function
+    ///
+    /// # Arguments
+    ///
+    /// * 'sealed' - base64 encoded sealed name
+    pub fn open(&self, sealed: &str) -> Result<String, CryptoError> {
+        let sealed = BASE64.decode(sealed)?;
+        if sealed.len() <= WRAP_NONCE_SIZE {
+            return Err(CryptoError("sealed name too short".to_string()));
+        }
+
+        let (nonce, sealed) = sealed.split_at(WRAP_NONCE_SIZE);
+        let name = self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: NAME_AAD })?;
+
+        String::from_utf8(name).map_err(|e| CryptoError(e.to_string()))
+    }
+}
+
+/// Segmented AEAD stream where each segment nonce is built from a random per-file prefix,
+/// a segment counter and a last segment flag, so that reordered, dropped or truncated
+/// segments fail authentication
+///
+struct Stream {
+    cipher: Aes256Gcm,
+    prefix: [u8; PREFIX_SIZE],
+    counter: u32,
+}
+
+impl Stream {
+
+    /// Creates a stream from data key and nonce prefix
+    ///
+    /// # Arguments
+    ///
+    /// * 'secret' - data key followed by nonce prefix
+    fn new(secret: &[u8]) -> Self {
+        let (key, prefix) = secret.split_at(KEY_SIZE);
+        let mut p = [0u8; PREFIX_SIZE];
+        p.copy_from_slice(prefix);
+
+        Stream { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)), prefix: p, counter: 0 }
+    }
+
+    /// Returns the nonce for the next segment and advances the counter
+    ///
+    /// # Arguments
+    ///
+    /// * 'last' - whether this is the last segment of the stream
+    fn next_nonce(&mut self, last: bool) -> Result<[u8; 12], CryptoError> {
+        let mut nonce = [0u8; 12];
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+
+        self.counter = self.counter.checked_add(1)
+            .ok_or(CryptoError("segment counter overflow".to_string()))?;
+
+        Ok(nonce)
+    }
+
+    /// Encrypts one segment
+    ///
+    /// # Arguments
+    ///
+    /// * 'segment' - plaintext segment
+    /// * 'last' - whether this is the last segment of the stream
+    fn seal(&mut self, segment: &[u8], last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = self.next_nonce(last)?;
+        Ok(self.cipher.encrypt(Nonce::from_slice(&nonce), segment)?)
+    }
+
+    /// Decrypts one segment
+    ///
+    /// # Arguments
+    ///
+    /// * 'segment' - ciphertext segment including tag
+    /// * 'last' - whether this is the last segment of the stream
+    fn open(&mut self, segment: &[u8], last: bool) -> Result<Vec<u8>, CryptoError> {
+        let nonce = self.next_nonce(last)?;
+        Ok(self.cipher.decrypt(Nonce::from_slice(&nonce), segment)?)
+    }
+}
+
+/// Streaming encryptor for one file
+/// Data can be given in any sized pieces, the ciphertext is emitted in whole segments
+///
+pub struct Encryptor {
+    stream: Stream,
+    buf: Vec<u8>,
+}
+
+impl Encryptor {
+
+    /// Encrypts data and returns whatever whole segments are available
+    /// The last segment is always held back until finish is called
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - plaintext
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.buf.extend_from_slice(data);
+
+        let mut out: Vec<u8> = Vec::new();
+        let mut offset = 0;
+        while self.buf.len() - offset > SEGMENT_SIZE {
+            out.extend(self.stream.seal(&self.buf[offset..offset + SEGMENT_SIZE], false)?);
+            offset += SEGMENT_SIZE;
+        }
+        self.buf.drain(..offset);
+
+        Ok(out)
+    }
+
+    /// Encrypts and returns the last segment
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, CryptoError> {
+        let last = std::mem::take(&mut self.buf);
+        self.stream.seal(&last, true)
+    }
+}
+
+/// Streaming decryptor for one file
+/// Ciphertext can be given in any sized pieces, plaintext is emitted per whole segment
+///
+pub struct Decryptor {
+    stream: Stream,
+    buf: Vec<u8>,
+}
+
+impl Decryptor {
+
+    /// Decrypts data and returns the plaintext of whatever whole segments are available
+    /// The last segment is always held back until finish is called
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - ciphertext
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.buf.extend_from_slice(data);
+
+        let mut out: Vec<u8> = Vec::new();
+        let mut offset = 0;
+        while self.buf.len() - offset > SEGMENT_SIZE + TAG_SIZE {
+            out.extend(self.stream.open(&self.buf[offset..offset + SEGMENT_SIZE + TAG_SIZE], false)?);
+            offset += SEGMENT_SIZE + TAG_SIZE;
+        }
+        self.buf.drain(..offset);
+
+        Ok(out)
+    }
+
+    /// Decrypts and returns the last segment
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, CryptoError> {
+        let last = std::mem::take(&mut self.buf);
+        if last.len() < TAG_SIZE {
+            return Err(CryptoError("truncated ciphertext".to_string()));
+        }
+
+        self.stream.open(&last, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const OTHER_KEY: &str = "Hx4dHBsaGRgXFhUUExIREA8ODQwLCgkIBwYFBAMCAQA=";
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn encrypt(key: &MasterKey, data: &[u8], piece: usize) -> (Vec<u8>, String) {
+        let (mut encryptor, wrapped) = key.encryptor().unwrap();
+        let mut ciphertext = Vec::new();
+        for chunk in data.chunks(piece) {
+            ciphertext.extend(encryptor.update(chunk).unwrap());
+        }
+        ciphertext.extend(encryptor.finish().unwrap());
+
+        (ciphertext, wrapped)
+    }
+
+    fn decrypt(key: &MasterKey, wrapped: &str, ciphertext: &[u8], piece: usize) -> Result<Vec<u8>, CryptoError> {
+        let mut decryptor = key.decryptor(wrapped)?;
+        let mut plaintext = Vec::new();
+        for chunk in ciphertext.chunks(piece) {
+            plaintext.extend(decryptor.update(chunk)?);
+        }
+        plaintext.extend(decryptor.finish()?);
+
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = MasterKey::new(KEY).unwrap();
+        for len in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 12345] {
+            let data = plaintext(len);
+            let (ciphertext, wrapped) = encrypt(&key, &data, 10000);
+            assert_eq!(ciphertext.len(), len + len.div_ceil(SEGMENT_SIZE).max(1) * TAG_SIZE);
+            assert_eq!(decrypt(&key, &wrapped, &ciphertext, 7777).unwrap(), data);
+            assert_eq!(decrypt(&key, &wrapped, &ciphertext, ciphertext.len().max(1)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn truncation_rejected() {
+        let key = MasterKey::new(KEY).unwrap();
+        let data = plaintext(2 * SEGMENT_SIZE + 100);
+        let (ciphertext, wrapped) = encrypt(&key, &data, data.len());
+
+        // dropping the last segment leaves a full segment that wasn't sealed as the last one
+        let segment = SEGMENT_SIZE + TAG_SIZE;
+        assert!(decrypt(&key, &wrapped, &ciphertext[..2 * segment], segment).is_err());
+        assert!(decrypt(&key, &wrapped, &ciphertext[..ciphertext.len() - 1], segment).is_err());
+        assert!(decrypt(&key, &wrapped, &ciphertext[..TAG_SIZE - 1], segment).is_err());
+    }
+
+    #[test]
+    fn reordered_segments_rejected() {
+        let key = MasterKey::new(KEY).unwrap();
+        let data = plaintext(3 * SEGMENT_SIZE + 100);
+        let (ciphertext, wrapped) = encrypt(&key, &data, data.len());
+
+        let segment = SEGMENT_SIZE + TAG_SIZE;
+        let mut reordered = ciphertext[segment..2 * segment].to_vec();
+        reordered.extend_from_slice(&ciphertext[..segment]);
+        reordered.extend_from_slice(&ciphertext[2 * segment..]);
+        assert!(decrypt(&key, &wrapped, &reordered, segment).is_err());
+    }
+
+    #[test]
+    fn wrong_key_rejected() {
+        let key = MasterKey::new(KEY).unwrap();
+        let other = MasterKey::new(OTHER_KEY).unwrap();
+        let (ciphertext, wrapped) = encrypt(&key, &plaintext(1000), 1000);
+
+        assert!(other.decryptor(&wrapped).is_err());
+        let (_, other_wrapped) = other.encryptor().unwrap();
+        assert!(decrypt(&other, &other_wrapped, &ciphertext, 1000).is_err());
+    }
+
+    #[test]
+    fn sealed_name_round_trip() {
+        let key = MasterKey::new(KEY).unwrap().name_key();
+        let sealed = key.seal("Documents/Résumé.pdf").unwrap();
+        assert_eq!(key.open(&sealed).unwrap(), "Documents/Résumé.pdf");
+        assert!(MasterKey::new(OTHER_KEY).unwrap().name_key().open(&sealed).is_err());
+    }
+}
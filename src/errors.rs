@@ -3,10 +3,15 @@ use std::fmt::{Display, Formatter};
 use aws_sdk_s3::operation::put_object::PutObjectError;
 use aws_sdk_s3::config::http::HttpResponse;
 use aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadError;
+use aws_sdk_s3::operation::copy_object::CopyObjectError;
 use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadError;
+use aws_sdk_s3::operation::delete_object::DeleteObjectError;
+use aws_sdk_s3::operation::get_object::GetObjectError;
 use aws_sdk_s3::operation::head_object::HeadObjectError;
 use aws_sdk_s3::operation::list_objects_v2::ListObjectsV2Error;
 use aws_sdk_s3::operation::upload_part::UploadPartError;
+use aws_sdk_s3::operation::upload_part_copy::UploadPartCopyError;
+use aws_sdk_s3::primitives::ByteStreamError;
 use aws_smithy_runtime_api::client::result::SdkError;
 use log4rs::config::runtime::ConfigErrors;
 use log::SetLoggerError;
@@ -31,6 +36,21 @@ impl From<std::io::Error> for UnrecoverableError {
         UnrecoverableError(e.to_string())
     }
 }
+impl From<RestoreError> for UnrecoverableError {
+    fn from(e: RestoreError) -> Self {
+        UnrecoverableError(e.to_string())
+    }
+}
+impl From<HistoryError> for UnrecoverableError {
+    fn from(e: HistoryError) -> Self {
+        UnrecoverableError(e.to_string())
+    }
+}
+impl From<MigrationError> for UnrecoverableError {
+    fn from(e: MigrationError) -> Self {
+        UnrecoverableError(e.to_string())
+    }
+}
 
 /// Errors while managing configuration
 /// 
@@ -77,6 +97,8 @@ pub enum TokenError {
     RefreshTokenExpired,
     FileIO(String),
     Request(String),
+    Transient(String),
+    OAuth(String),
 }
 impl fmt::Display for TokenError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -85,6 +107,8 @@ impl fmt::Display for TokenError {
             TokenError::RefreshTokenExpired => write!(f, "TokenError::RefreshTokenExpired"),
             TokenError::FileIO(e)   => write!(f, "TokenError::File: {}", e),
             TokenError::Request(e)  => write!(f, "TokenError::Request: {}", e),
+            TokenError::Transient(e) => write!(f, "TokenError::Transient: {}", e),
+            TokenError::OAuth(e)    => write!(f, "TokenError::OAuth: {}", e),
         }
     }
 }
@@ -108,11 +132,15 @@ impl From<reqwest::Error> for TokenError {
 /// Errors from main sync loop
 ///
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CloudSyncError {
     TokenExpiredWarning,
     TokenError(String),
     OneDrive(String),
     AWS(String),
+    Transform(String),
+    Manifest(String),
+    Verification(String),
 }
 impl fmt::Display for CloudSyncError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -121,6 +149,24 @@ impl fmt::Display for CloudSyncError {
             CloudSyncError::TokenError(e) => write!(f, "CloudSyncError::TokenError: {}", e),
             CloudSyncError::OneDrive(e)   => write!(f, "CloudSyncError::OneDrive: {}", e),
             CloudSyncError::AWS(e)        => write!(f, "CloudSyncError::AWS: {}", e),
+            CloudSyncError::Transform(e)  => write!(f, "CloudSyncError::Transform: {}", e),
+            CloudSyncError::Manifest(e)   => write!(f, "CloudSyncError::Manifest: {}", e),
+            CloudSyncError::Verification(e) => write!(f, "CloudSyncError::Verification: {}", e),
+        }
+    }
+}
+impl CloudSyncError {
+    /// Returns a short name of the kind of error, e.g. for use as a metrics label
+    ///
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CloudSyncError::TokenExpiredWarning => "token_expired",
+            CloudSyncError::TokenError(_) => "token",
+            CloudSyncError::OneDrive(_)   => "onedrive",
+            CloudSyncError::AWS(_)        => "aws",
+            CloudSyncError::Transform(_)  => "transform",
+            CloudSyncError::Manifest(_)   => "manifest",
+            CloudSyncError::Verification(_) => "verification",
         }
     }
 }
@@ -131,6 +177,8 @@ impl From<TokenError> for CloudSyncError {
             TokenError::RefreshTokenExpired => { CloudSyncError::TokenExpiredWarning }
             TokenError::FileIO(err) => { CloudSyncError::TokenError(err) }
             TokenError::Request(err) => { CloudSyncError::TokenError(err) }
+            TokenError::Transient(err) => { CloudSyncError::TokenError(err) }
+            TokenError::OAuth(err) => { CloudSyncError::TokenError(err) }
         }
     }
 }
@@ -140,6 +188,18 @@ impl From<OneDriveError> for CloudSyncError {
 impl From<AWSError> for CloudSyncError {
     fn from(e: AWSError) -> Self { CloudSyncError::AWS(e.to_string()) }
 }
+impl From<TransformError> for CloudSyncError {
+    fn from(e: TransformError) -> Self { CloudSyncError::Transform(e.to_string()) }
+}
+impl From<CryptoError> for CloudSyncError {
+    fn from(e: CryptoError) -> Self { CloudSyncError::Transform(e.to_string()) }
+}
+impl From<ManifestError> for CloudSyncError {
+    fn from(e: ManifestError) -> Self { CloudSyncError::Manifest(e.to_string()) }
+}
+impl From<ObjectKeyError> for CloudSyncError {
+    fn from(e: ObjectKeyError) -> Self { CloudSyncError::Transform(e.to_string()) }
+}
 
 /// Errors while managing OneDrive
 ///
@@ -173,6 +233,7 @@ impl From<std::io::Error> for OneDriveError {
 
 /// Errors while managing AWS
 ///
+#[derive(Debug)]
 pub struct AWSError(pub String);
 impl fmt::Display for AWSError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -188,6 +249,12 @@ impl From<aws_sdk_s3::Error> for AWSError {
 impl From<SdkError<PutObjectError, HttpResponse>> for AWSError {
     fn from(e: SdkError<PutObjectError, HttpResponse>) -> Self { AWSError(e.to_string()) }
 }
+impl From<SdkError<GetObjectError, HttpResponse>> for AWSError {
+    fn from(e: SdkError<GetObjectError, HttpResponse>) -> Self { AWSError(e.to_string()) }
+}
+impl From<ByteStreamError> for AWSError {
+    fn from(e: ByteStreamError) -> Self { AWSError(e.to_string()) }
+}
 impl From<SdkError<HeadObjectError, HttpResponse>> for AWSError {
     fn from(e: SdkError<HeadObjectError, HttpResponse>) -> Self { AWSError(e.to_string()) }
 }
@@ -203,6 +270,166 @@ impl From<SdkError<UploadPartError, HttpResponse>> for AWSError {
 impl From<SdkError<CompleteMultipartUploadError, HttpResponse>> for AWSError {
     fn from(e: SdkError<CompleteMultipartUploadError, HttpResponse>) -> Self { AWSError(e.to_string()) }
 }
+impl From<SdkError<CopyObjectError, HttpResponse>> for AWSError {
+    fn from(e: SdkError<CopyObjectError, HttpResponse>) -> Self { AWSError(e.to_string()) }
+}
+impl From<SdkError<UploadPartCopyError, HttpResponse>> for AWSError {
+    fn from(e: SdkError<UploadPartCopyError, HttpResponse>) -> Self { AWSError(e.to_string()) }
+}
+impl From<SdkError<DeleteObjectError, HttpResponse>> for AWSError {
+    fn from(e: SdkError<DeleteObjectError, HttpResponse>) -> Self { AWSError(e.to_string()) }
+}
+
+/// Errors while encrypting or decrypting
+///
+#[derive(Debug)]
+pub struct CryptoError(pub String);
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "CryptoError: {}", self.0)
+    }
+}
+impl From<base64::DecodeError> for CryptoError {
+    fn from(e: base64::DecodeError) -> Self { CryptoError(e.to_string()) }
+}
+impl From<aes_gcm::Error> for CryptoError {
+    fn from(_: aes_gcm::Error) -> Self { CryptoError("authentication failed".to_string()) }
+}
+
+/// Errors while transforming content between OneDrive and S3
+///
+#[derive(Debug)]
+pub struct TransformError(pub String);
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TransformError: {}", self.0)
+    }
+}
+impl From<CryptoError> for TransformError {
+    fn from(e: CryptoError) -> Self { TransformError(e.to_string()) }
+}
+impl From<std::io::Error> for TransformError {
+    fn from(e: std::io::Error) -> Self { TransformError(e.to_string()) }
+}
+
+/// Errors while restoring objects from S3
+///
+pub struct RestoreError(pub String);
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "RestoreError: {}", self.0)
+    }
+}
+impl From<AWSError> for RestoreError {
+    fn from(e: AWSError) -> Self { RestoreError(e.to_string()) }
+}
+impl From<TransformError> for RestoreError {
+    fn from(e: TransformError) -> Self { RestoreError(e.to_string()) }
+}
+impl From<CryptoError> for RestoreError {
+    fn from(e: CryptoError) -> Self { RestoreError(e.to_string()) }
+}
+impl From<std::io::Error> for RestoreError {
+    fn from(e: std::io::Error) -> Self { RestoreError(e.to_string()) }
+}
+impl From<ManifestError> for RestoreError {
+    fn from(e: ManifestError) -> Self { RestoreError(e.to_string()) }
+}
+impl From<ObjectKeyError> for RestoreError {
+    fn from(e: ObjectKeyError) -> Self { RestoreError(e.to_string()) }
+}
+
+/// Errors while mapping OneDrive paths to object keys
+///
+#[derive(Debug)]
+pub struct ObjectKeyError(pub String);
+impl fmt::Display for ObjectKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectKeyError: {}", self.0)
+    }
+}
+impl From<CryptoError> for ObjectKeyError {
+    fn from(e: CryptoError) -> Self { ObjectKeyError(e.to_string()) }
+}
+
+/// Errors while migrating objects to a new key layout
+///
+#[derive(Debug)]
+pub struct MigrationError(pub String);
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "MigrationError: {}", self.0)
+    }
+}
+impl From<AWSError> for MigrationError {
+    fn from(e: AWSError) -> Self { MigrationError(e.to_string()) }
+}
+impl From<CryptoError> for MigrationError {
+    fn from(e: CryptoError) -> Self { MigrationError(e.to_string()) }
+}
+impl From<ObjectKeyError> for MigrationError {
+    fn from(e: ObjectKeyError) -> Self { MigrationError(e.to_string()) }
+}
+impl From<ManifestError> for MigrationError {
+    fn from(e: ManifestError) -> Self { MigrationError(e.to_string()) }
+}
+
+/// Errors while scrubbing stored objects
+///
+pub struct ScrubError(pub String);
+impl fmt::Display for ScrubError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ScrubError: {}", self.0)
+    }
+}
+impl From<AWSError> for ScrubError {
+    fn from(e: AWSError) -> Self { ScrubError(e.to_string()) }
+}
+impl From<OneDriveError> for ScrubError {
+    fn from(e: OneDriveError) -> Self { ScrubError(e.to_string()) }
+}
+impl From<TokenError> for ScrubError {
+    fn from(e: TokenError) -> Self { ScrubError(e.to_string()) }
+}
+impl From<TransformError> for ScrubError {
+    fn from(e: TransformError) -> Self { ScrubError(e.to_string()) }
+}
+
+/// Errors while managing the run history
+///
+pub struct HistoryError(pub String);
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "HistoryError: {}", self.0)
+    }
+}
+impl From<std::io::Error> for HistoryError {
+    fn from(e: std::io::Error) -> Self { HistoryError(e.to_string()) }
+}
+impl From<serde_json::Error> for HistoryError {
+    fn from(e: serde_json::Error) -> Self { HistoryError(e.to_string()) }
+}
+
+/// Errors while managing the object manifest
+///
+pub struct ManifestError(pub String);
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ManifestError: {}", self.0)
+    }
+}
+impl From<std::io::Error> for ManifestError {
+    fn from(e: std::io::Error) -> Self { ManifestError(e.to_string()) }
+}
+impl From<serde_json::Error> for ManifestError {
+    fn from(e: serde_json::Error) -> Self { ManifestError(e.to_string()) }
+}
+impl From<AWSError> for ManifestError {
+    fn from(e: AWSError) -> Self { ManifestError(e.to_string()) }
+}
+impl From<TransformError> for ManifestError {
+    fn from(e: TransformError) -> Self { ManifestError(e.to_string()) }
+}
 
 /// Errors while managing mail
 /// 
@@ -216,4 +443,33 @@ impl From<lettre::transport::smtp::Error> for MailError {
 }
 impl From<lettre::error::Error> for MailError {
     fn from(e: lettre::error::Error) -> Self { MailError(e.to_string()) }
+}
+impl From<std::io::Error> for MailError {
+    fn from(e: std::io::Error) -> Self { MailError(e.to_string()) }
+}
+impl From<serde_json::Error> for MailError {
+    fn from(e: serde_json::Error) -> Self { MailError(e.to_string()) }
+}
+
+/// Errors while sending notifications
+///
+pub struct NotifyError(pub String);
+
+impl Display for NotifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {write!(f, "NotifyError: {}", self.0)}
+}
+impl From<MailError> for NotifyError {
+    fn from(e: MailError) -> Self { NotifyError(e.to_string()) }
+}
+impl From<lettre::transport::smtp::Error> for NotifyError {
+    fn from(e: lettre::transport::smtp::Error) -> Self { NotifyError(e.to_string()) }
+}
+impl From<lettre::transport::sendmail::Error> for NotifyError {
+    fn from(e: lettre::transport::sendmail::Error) -> Self { NotifyError(e.to_string()) }
+}
+impl From<lettre::transport::file::Error> for NotifyError {
+    fn from(e: lettre::transport::file::Error) -> Self { NotifyError(e.to_string()) }
+}
+impl From<reqwest::Error> for NotifyError {
+    fn from(e: reqwest::Error) -> Self { NotifyError(e.to_string()) }
 }
\ No newline at end of file
@@ -0,0 +1,274 @@
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use crate::errors::HistoryError;
+use crate::initialization::General;
+use crate::status::escape;
+
+/// Number of days runs are kept in the history if no retention is configured
+const DEFAULT_RETENTION_DAYS: u32 = 90;
+
+/// Format of run ids, which are the run start times in UTC
+const RUN_ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// What started a sync run
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Scheduled,
+    Retry,
+}
+
+impl Trigger {
+
+    /// Returns the trigger as shown in listings
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Scheduled => "scheduled",
+            Trigger::Retry     => "retry",
+        }
+    }
+}
+
+/// A file stored or moved during a run
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Change {
+    pub action: String,
+    pub key: String,
+    pub path: String,
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// Report of one sync run
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RunRecord {
+    pub id: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub trigger: Trigger,
+    pub success: bool,
+    pub error: Option<String>,
+    pub updated: u32,
+    pub added: u32,
+    pub renamed: u32,
+    #[serde(default)]
+    pub skipped: u32,
+    #[serde(default)]
+    pub excluded: u32,
+    pub bytes: u64,
+    #[serde(default)]
+    pub verified: u32,
+    #[serde(default)]
+    pub verify_retried: u32,
+    #[serde(default)]
+    pub verify_failed: u32,
+    pub failures: Vec<String>,
+    pub changes: Vec<Change>,
+}
+
+impl RunRecord {
+
+    /// Returns a new record for a run starting now
+    ///
+    /// # Arguments
+    ///
+    /// * 'trigger' - what started the run
+    pub fn new(trigger: Trigger) -> Self {
+        let started = Utc::now();
+        RunRecord {
+            id: started.format(RUN_ID_FORMAT).to_string(),
+            started,
+            finished: None,
+            trigger,
+            success: false,
+            error: None,
+            updated: 0,
+            added: 0,
+            renamed: 0,
+            skipped: 0,
+            excluded: 0,
+            bytes: 0,
+            verified: 0,
+            verify_retried: 0,
+            verify_failed: 0,
+            failures: Vec::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    /// Records a file stored or moved during the run
+    ///
+    /// # Arguments
+    ///
+    /// * 'action' - what was done, "added", "updated" or "renamed"
+    /// * 'key' - the object key
+    /// * 'path' - the OneDrive path
+    /// * 'size' - size of the file
+    pub fn change(&mut self, action: &str, key: &str, path: &str, size: u64) {
+        self.changes.push(Change { action: action.to_string(), key: key.to_string(), path: path.to_string(), size });
+    }
+
+    /// Returns the run duration in seconds, zero if the run hasn't finished
+    ///
+    pub fn duration(&self) -> i64 {
+        self.finished.map(|f| (f - self.started).num_seconds()).unwrap_or_default()
+    }
+
+    /// Marks the run as finished
+    ///
+    /// # Arguments
+    ///
+    /// * 'error' - the error ending the run, if it failed
+    pub fn finish(&mut self, error: Option<String>) {
+        self.finished = Some(Utc::now());
+        self.success = error.is_none();
+        self.error = error;
+    }
+
+    /// Returns a one line summary of the run
+    ///
+    pub fn summary(&self) -> String {
+        format!("{}  {:<9}  {:<6}  updates: {}, adds: {}, renames: {}, bytes: {}, failures: {}",
+            self.id,
+            self.trigger.as_str(),
+            if self.success { "ok" } else { "failed" },
+            self.updated, self.added, self.renamed, self.bytes, self.failures.len())
+    }
+}
+
+/// Local store of run reports, one JSON file per run in the history directory
+/// Runs older than the retention are pruned whenever a run is saved
+///
+pub struct History {
+    dir: Option<PathBuf>,
+    retention_days: u32,
+}
+
+impl History {
+
+    /// Returns the history given by configuration, which is disabled if no directory is configured
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - general configuration parameters
+    pub fn new(config: &General) -> Self {
+        History {
+            dir: config.history_dir.as_ref().map(PathBuf::from),
+            retention_days: config.history_retention_days.unwrap_or(DEFAULT_RETENTION_DAYS),
+        }
+    }
+
+    /// Saves a run report and prunes runs older than the retention
+    ///
+    /// # Arguments
+    ///
+    /// * 'record' - the run report
+    pub async fn save(&self, record: &RunRecord) -> Result<(), HistoryError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        tokio::fs::create_dir_all(dir).await?;
+        let json = serde_json::to_string_pretty(record)?;
+        tokio::fs::write(dir.join(format!("{}.json", record.id)), json).await?;
+
+        self.prune(dir).await
+    }
+
+    /// Returns all runs in the history, the latest first
+    ///
+    pub async fn runs(&self) -> Result<Vec<RunRecord>, HistoryError> {
+        let dir = self.dir()?;
+
+        let mut runs: Vec<RunRecord> = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|e| e == "json") {
+                let json = tokio::fs::read_to_string(entry.path()).await?;
+                runs.push(serde_json::from_str(&json)?);
+            }
+        }
+        runs.sort_by_key(|r| Reverse(r.started));
+
+        Ok(runs)
+    }
+
+    /// Returns a run from the history, or None if there is no run with the given id
+    ///
+    /// # Arguments
+    ///
+    /// * 'id' - the run id
+    pub async fn run(&self, id: &str) -> Result<Option<RunRecord>, HistoryError> {
+        let dir = self.dir()?;
+        if NaiveDateTime::parse_from_str(id, RUN_ID_FORMAT).is_err() {
+            return Ok(None);
+        }
+
+        let path = dir.join(format!("{}.json", id));
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = tokio::fs::read_to_string(path).await?;
+
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    /// Returns the history directory, or an error if history is disabled
+    ///
+    fn dir(&self) -> Result<&PathBuf, HistoryError> {
+        self.dir.as_ref().ok_or(HistoryError("no history_dir configured".to_string()))
+    }
+
+    /// Removes runs started before the retention period
+    ///
+    /// # Arguments
+    ///
+    /// * 'dir' - the history directory
+    async fn prune(&self, dir: &Path) -> Result<(), HistoryError> {
+        let oldest = Utc::now() - TimeDelta::days(self.retention_days as i64);
+
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let started = path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| NaiveDateTime::parse_from_str(s, RUN_ID_FORMAT).ok());
+            if started.is_some_and(|s| s.and_utc() < oldest) {
+                tokio::fs::remove_file(&path).await?;
+                info!("pruned run from history: {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders the run history as a simple HTML page, linking to the full report of each run
+///
+/// # Arguments
+///
+/// * 'runs' - the runs in the history, the latest first
+pub fn history_page(runs: &[RunRecord]) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <title>CloudSync history</title><style>body{font-family:sans-serif;margin:2em}td,th{padding:2px 12px;text-align:left}</style>\
+        </head><body><h1>CloudSync history</h1>\n<table>\n\
+        <tr><th>Run</th><th>Trigger</th><th>Result</th><th>Updates</th><th>Adds</th><th>Renames</th><th>Bytes</th><th>Failures</th></tr>\n");
+
+    for run in runs {
+        let result = match &run.error {
+            Some(e) => format!("failed: {}", e),
+            None => "ok".to_string(),
+        };
+        html.push_str(&format!("<tr><td><a href=\"/history/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            run.id, run.started.format("%Y-%m-%d %H:%M:%S UTC"), run.trigger.as_str(), escape(&result),
+            run.updated, run.added, run.renamed, run.bytes, run.failures.len()));
+    }
+    html.push_str("</table>\n</body></html>\n");
+
+    html
+}
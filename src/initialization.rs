@@ -1,9 +1,14 @@
 use std::{env, fs};
-use std::path::PathBuf;
-use serde::Deserialize;
-use tokio::sync::mpsc::{UnboundedSender};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Deserializer};
+use tokio::sync::mpsc::Sender;
 use crate::errors::ConfigError;
 use crate::logging::setup_logger;
+use crate::mail_manager::{Mail, MailTransport, SmtpTls};
+use crate::notifier::{NotifierKind, Severity};
+
+/// Name of the object manifest file, kept next to the delta link file if no path is configured
+const DEFAULT_MANIFEST_FILE: &str = "manifest.json";
 
 #[derive(Deserialize, Clone)]
 pub struct OneDrive {
@@ -15,9 +20,16 @@ pub struct OneDrive {
     pub scope: String,
     pub tokens_path: String,
     pub delta_link_path: String,
+    #[serde(default)]
+    pub refresh_token_lifetime_days: Option<u32>,
+    #[serde(default)]
+    pub max_grant_age_days: Option<u32>,
+    #[serde(default)]
+    pub token_warning_days: Option<u32>,
 }
 
 #[derive(Deserialize, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct AWS {
     #[serde(default)]
     access_key_id: String,
@@ -25,17 +37,153 @@ pub struct AWS {
     secret_access_key: String,
     region: String,
     pub bucket: String,
+    #[serde(default)]
+    pub endpoint_url: Option<String>,
+    #[serde(default)]
+    pub force_path_style: bool,
+    #[serde(default)]
+    pub request_checksum_calculation: Option<String>,
+    #[serde(default)]
+    pub response_checksum_validation: Option<String>,
+    #[serde(default)]
+    pub default_storage_class: Option<String>,
+    #[serde(default)]
+    pub storage_class_rules: Vec<StorageClassRule>,
+    #[serde(default)]
+    pub server_side_encryption: Option<String>,
+    #[serde(default)]
+    pub sse_kms_key_id: Option<String>,
+    #[serde(default)]
+    pub sse_bucket_key_enabled: bool,
+    #[serde(default)]
+    pub sse_customer_key: String,
+    #[serde(default)]
+    pub client_side_encryption: bool,
+    #[serde(default)]
+    pub obfuscate_keys: bool,
+    #[serde(default)]
+    pub deduplicate: bool,
+    #[serde(default)]
+    pub cse_master_key: String,
+    #[serde(default)]
+    pub key_prefix: Option<String>,
+    #[serde(default)]
+    pub key_template: Option<String>,
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub part_size: Option<u64>,
+    #[serde(default)]
+    pub multipart_threshold: Option<u64>,
+    #[serde(default)]
+    pub checksum_algorithm: Option<String>,
+    #[serde(default)]
+    pub verify_uploads: bool,
+    #[serde(default)]
+    pub verify_attempts: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StorageClassRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    pub storage_class: String,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Compression {
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub level: Option<i32>,
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Scrub {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub sample_size: Option<usize>,
+    #[serde(default)]
+    pub rotation_days: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct MailParameters {
+    #[serde(default)]
+    pub transport: MailTransport,
     #[serde(default)]
     pub smtp_user: String,
     #[serde(default)]
     pub smtp_password: String,
+    #[serde(default)]
     pub smtp_endpoint: String,
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub smtp_tls: SmtpTls,
+    #[serde(default)]
+    pub smtp_auth: Option<bool>,
+    #[serde(default)]
+    pub sendmail_command: Option<String>,
+    #[serde(default)]
+    pub file_dir: Option<String>,
     pub from: String,
-    pub to: String,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub cc: Vec<String>,
+    #[serde(default)]
+    pub recipients: Vec<Recipient>,
+    #[serde(default)]
+    pub batch_window_secs: Option<u64>,
+    #[serde(default)]
+    pub max_mails_per_hour: Option<usize>,
+    #[serde(default)]
+    pub spool_dir: Option<String>,
+    #[serde(default)]
+    pub max_send_attempts: Option<u32>,
+    #[serde(default)]
+    pub fallback_path: Option<String>,
+    #[serde(default)]
+    pub min_severity: Option<Severity>,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Recipient {
+    pub address: String,
+    #[serde(default)]
+    pub cc: bool,
+    #[serde(default)]
+    pub min_severity: Option<Severity>,
+}
+
+#[derive(Deserialize)]
+pub struct NotifierParameters {
+    pub name: String,
+    pub kind: NotifierKind,
+    pub url: String,
+    #[serde(default)]
+    pub token_credential: Option<String>,
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub min_severity: Option<Severity>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub batch_window_secs: Option<u64>,
+    #[serde(default)]
+    pub max_per_hour: Option<usize>,
 }
 
 #[derive(Deserialize)]
@@ -49,36 +197,70 @@ pub struct WebServerParameters {
 pub struct General {
     pub sync_time: String,
     pub log_path: String,
+    #[serde(default)]
+    pub manifest_path: Option<String>,
+    #[serde(default)]
+    pub history_dir: Option<String>,
+    #[serde(default)]
+    pub history_retention_days: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
     pub onedrive: OneDrive,
     pub aws: AWS,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub scrub: Scrub,
     pub mail: MailParameters,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierParameters>,
     pub web_server: WebServerParameters,
     pub general: General,
 }
 
+impl Config {
+
+    /// Returns the path of the object manifest file, by default manifest.json in the directory
+    /// of the delta link file
+    ///
+    pub fn manifest_path(&self) -> String {
+        match &self.general.manifest_path {
+            Some(path) => path.clone(),
+            None => Path::new(&self.onedrive.delta_link_path)
+                .with_file_name(DEFAULT_MANIFEST_FILE)
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+}
+
 /// Returns a configuration struct for the application and starts logging
 /// 
-pub fn config(tx: UnboundedSender<String>) -> Result<Config, ConfigError> {
-    let args: Vec<String> = env::args().collect();
-    let config_path = args.iter()
-        .find(|p| p.starts_with("--config="))
-        .expect("config file argument should be present");
-    let config_path = config_path
-        .split_once('=')
-        .expect("config file argument should be correct")
-        .1;
+pub fn config(tx: Sender<Mail>) -> Result<Config, ConfigError> {
+    let config_path = argument("config").expect("config file argument should be present");
 
     let mut config = load_config(&config_path)?;
     config.onedrive.client_id = read_credential("onedrive_client_id")?;
     config.onedrive.client_secret = read_credential("onedrive_client_secret")?;
     config.aws.access_key_id = read_credential("aws_access_key_id")?;
     config.aws.secret_access_key = read_credential("aws_secret_access_key")?;
-    config.mail.smtp_user = read_credential("mail_smtp_user")?;
-    config.mail.smtp_password = read_credential("mail_smtp_password")?;
+    if config.aws.server_side_encryption.as_deref() == Some("SSE-C") {
+        config.aws.sse_customer_key = read_credential("aws_sse_customer_key")?;
+    }
+    if config.aws.client_side_encryption {
+        config.aws.cse_master_key = read_credential("cse_master_key")?;
+    }
+    if config.mail.transport == MailTransport::Smtp && config.mail.smtp_auth.unwrap_or(true) {
+        config.mail.smtp_user = read_credential("mail_smtp_user")?;
+        config.mail.smtp_password = read_credential("mail_smtp_password")?;
+    }
+    for notifier in config.notifiers.iter_mut() {
+        if let Some(name) = &notifier.token_credential {
+            notifier.token = read_credential(name)?;
+        }
+    }
     
     env::set_var("AWS_ACCESS_KEY_ID", &config.aws.access_key_id);
     env::set_var("AWS_SECRET_ACCESS_KEY", &config.aws.secret_access_key);
@@ -89,6 +271,37 @@ pub fn config(tx: UnboundedSender<String>) -> Result<Config, ConfigError> {
     Ok(config)
 }
 
+/// Deserializes either a single string or a list of strings
+///
+/// # Arguments
+///
+/// * 'deserializer' - the deserializer
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Returns the value of a command line argument given as --name=value
+///
+/// # Arguments
+///
+/// * 'name' - name of the argument without leading dashes
+pub fn argument(name: &str) -> Option<String> {
+    let prefix = format!("--{}=", name);
+    env::args()
+        .find(|a| a.starts_with(&prefix))
+        .map(|a| a[prefix.len()..].to_string())
+}
+
 /// Loads the configuration file and returns a struct with all configuration items
 ///
 /// # Arguments
@@ -8,8 +8,12 @@ use log4rs::encode::Encode;
 use log4rs::encode::pattern::PatternEncoder;
 use log::{LevelFilter, Record};
 use log4rs::encode::writer::simple::SimpleWriter;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
 use crate::errors::ConfigError;
+use crate::mail_manager::Mail;
+use crate::metrics::METRICS;
+use crate::notifier::Severity;
 
 /// Sets up the logger
 ///
@@ -17,7 +21,7 @@ use crate::errors::ConfigError;
 ///
 /// * 'log_path' - path where to save logs
 /// * 'tx' - mpsc sender
-pub fn setup_logger(log_path: &str, tx: UnboundedSender<String>) -> Result<(), ConfigError> {
+pub fn setup_logger(log_path: &str, tx: Sender<Mail>) -> Result<(), ConfigError> {
     let mail = MailAppender::builder()
         .encoder(Box::new(PatternEncoder::new("[{d(%Y-%m-%d %H:%M:%S %:z)} {l} {M}] - {m}{n}")))
         .writer(tx)
@@ -49,7 +53,7 @@ pub fn setup_logger(log_path: &str, tx: UnboundedSender<String>) -> Result<(), C
 ///
 struct MailAppenderBuilder {
     encoder: Option<Box<dyn Encode>>,
-    writer: Option<UnboundedSender<String>>
+    writer: Option<Sender<Mail>>
 }
 
 impl MailAppenderBuilder {
@@ -66,13 +70,13 @@ impl MailAppenderBuilder {
     /// Sets the output writer for the `MailAppender`
     /// 
     /// Since the `Append` trait isn't an async method we can't use the `Mail` struct directly
-    /// but rather using a `tokio::unbounded_channel` to communicate with a spawned mail
-    /// client loop which holds the `UnboundedReceiver` in the other end.
+    /// but rather using a bounded `tokio::sync::mpsc::channel` to communicate with a spawned mail
+    /// client loop which holds the `Receiver` in the other end.
     /// 
     /// # Arguments
     /// 
-    /// * 'writer' - expects an unbounded channel sender
-    fn writer(mut self, writer: UnboundedSender<String>) -> MailAppenderBuilder {
+    /// * 'writer' - expects a bounded channel sender
+    fn writer(mut self, writer: Sender<Mail>) -> MailAppenderBuilder {
         self.writer = Some(writer);
         self
     }
@@ -96,7 +100,7 @@ impl MailAppenderBuilder {
 struct MailAppender {
     encoder: Box<dyn Encode>,
     #[derivative(Debug = "ignore")]
-    writer: UnboundedSender<String>,
+    writer: Sender<Mail>,
 }
 impl MailAppender {
     /// Creates a new `MailAppender` builder
@@ -111,6 +115,7 @@ impl MailAppender {
 impl Append for MailAppender {
     /// Implementation of the `append` trait which gets a log record, encodes it to a buffer
     /// and is then sent to the spawned mail client loop
+    /// If the channel is full, e.g. during a log storm, the event is dropped rather than blocking
     /// 
     fn append(&self, record: &Record) -> anyhow::Result<()> {
         let data: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(Vec::new()));
@@ -119,7 +124,13 @@ impl Append for MailAppender {
 
         let text = String::from_utf8(data.lock().unwrap().to_vec())?;
 
-        self.writer.send(text)?;
+        let module = record.module_path().unwrap_or_default();
+        let key = format!("{} {} {}", record.level(), module, record.args());
+        let kind = module.rsplit("::").next().unwrap_or_default().to_string();
+        match self.writer.try_send(Mail::event(key, Severity::from_level(record.level()), kind, text)) {
+            Err(TrySendError::Full(_)) => METRICS.mails.with_label_values(&["dropped"]).inc(),
+            result => result?,
+        }
 
         Ok(())
     }
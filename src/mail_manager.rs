@@ -1,76 +1,414 @@
-use lettre::{AsyncTransport, Message, Tokio1Executor};
+use lettre::{AsyncFileTransport, AsyncSendmailTransport, AsyncTransport, Message, Tokio1Executor};
 use lettre::message::header::ContentType;
-use lettre::message::Mailbox;
+use lettre::message::{Attachment as MailAttachment, Mailbox, MultiPart, SinglePart};
 use lettre::transport::smtp::AsyncSmtpTransport;
 use lettre::transport::smtp::authentication::Credentials;
-use log::error;
-use tokio::sync::mpsc::UnboundedReceiver;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::time::{Duration, Instant};
 use crate::initialization::MailParameters;
-use crate::errors::MailError;
+use crate::errors::{MailError, NotifyError};
+use crate::metrics::{observe, result, METRICS};
+use crate::notifier::{Notifier, Severity};
 
+/// Capacity of the channel to the notifier, log events are dropped when it is full
+pub const MAIL_CHANNEL_CAPACITY: usize = 1000;
 
-/// Sends a mail whenever an event is received over the mpsc channel
-/// 
+/// Seconds log events are collected into one mail if no batch window is configured
+const DEFAULT_BATCH_WINDOW_SECS: u64 = 60;
+
+/// Maximum number of mails sent per hour if no limit is configured
+const DEFAULT_MAX_MAILS_PER_HOUR: usize = 20;
+
+/// Maximum number of distinct log events kept in a batch, further events are only counted
+const MAX_BATCH_EVENTS: usize = 100;
+
+/// A mail or other notification to send, either a log event or a report
+/// Log events have a key, identical events having the same key, and are batched by the notifier.
+/// The kind is the event type used for routing, "report" for run reports and the module for log
+/// events, e.g. "scrub"
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub key: Option<String>,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub kind: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Mail {
+
+    /// Returns a plain text mail for a log event
+    ///
+    /// # Arguments
+    ///
+    /// * 'key' - key identifying identical events, e.g. level, module and message
+    /// * 'severity' - severity of the event
+    /// * 'kind' - type of the event
+    /// * 'text' - the formatted log event
+    pub fn event(key: String, severity: Severity, kind: String, text: String) -> Self {
+        Mail {
+            key: Some(key),
+            severity,
+            kind,
+            subject: "CloudSync event".to_string(),
+            text,
+            html: None,
+            attachments: Vec::new(),
+        }
+    }
+}
+
+/// A file attached to a mail, with the content base64 encoded when spooled
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub content: Vec<u8>,
+}
+
+/// Serializes bytes as a base64 string
+///
 /// # Arguments
-/// 
-/// * 'config' - mail configuration parameters
-/// * 'rx' - mpsc receiver
-pub async fn mailer(config: &MailParameters, mut rx: UnboundedReceiver<String>) {
-    let sender = match sender(config) {
-        Ok(sender) => sender,
-        Err(e) => { error!("{}", e); panic!("invalid mail config!") }
-    };
+///
+/// * 'bytes' - the bytes to serialize
+/// * 'serializer' - the serializer
+fn to_base64<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(bytes))
+}
 
-    let from = config.from.parse::<Mailbox>().expect("invalid from mailbox config!");
-    let to = config.to.parse::<Mailbox>().expect("invalid to mailbox config!");
-
-    loop {
-        match rx.recv().await {
-            Some(body) => {
-                match message(&from, &to, "CloudSync event", body) {
-                    Ok(email) => {
-                        if let Err(e) = sender.send(email).await {
-                            error!("error sending mail: {}", e);
-                        }
-                    },
-                    Err(e) => { error!("{}", e); }
-                };
+/// Deserializes bytes from a base64 string
+///
+/// # Arguments
+///
+/// * 'deserializer' - the deserializer
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+/// How mails are sent
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    #[default]
+    Smtp,
+    Sendmail,
+    File,
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Implicit,
+    Starttls,
+    None,
+}
+
+/// The transport mails are sent with
+///
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+}
+
+/// A mail recipient, receiving only mails of at least the given severity
+///
+struct Recipient {
+    mailbox: Mailbox,
+    cc: bool,
+    min_severity: Severity,
+}
+
+/// Notification channel sending mails over SMTP, through a sendmail command or, for testing, to
+/// .eml files in a directory
+///
+pub struct MailNotifier {
+    transport: Transport,
+    from: Mailbox,
+    recipients: Vec<Recipient>,
+}
+
+impl MailNotifier {
+
+    /// Returns the mail channel given by configuration
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - mail configuration parameters
+    pub fn new(config: &MailParameters) -> Result<Self, MailError> {
+        let mut recipients: Vec<Recipient> = Vec::new();
+        for (address, cc) in config.to.iter().map(|a| (a, false)).chain(config.cc.iter().map(|a| (a, true))) {
+            recipients.push(Recipient { mailbox: mailbox(address)?, cc, min_severity: Severity::Info });
+        }
+        for r in &config.recipients {
+            recipients.push(Recipient { mailbox: mailbox(&r.address)?, cc: r.cc, min_severity: r.min_severity.unwrap_or_default() });
+        }
+        if recipients.is_empty() {
+            return Err(MailError("no mail recipients configured".to_string()));
+        }
+
+        Ok(MailNotifier {
+            transport: transport(config)?,
+            from: mailbox(&config.from)?,
+            recipients,
+        })
+    }
+}
+
+impl Notifier for MailNotifier {
+    fn name(&self) -> &str {
+        "mail"
+    }
+
+    fn send<'a>(&'a self, mail: &'a Mail) -> Pin<Box<dyn Future<Output = Result<(), NotifyError>> + Send + 'a>> {
+        Box::pin(async move {
+            let recipients: Vec<&Recipient> = self.recipients.iter().filter(|r| mail.severity >= r.min_severity).collect();
+            if recipients.is_empty() {
+                return Ok(());
             }
-            None => {
-                error!("communication channel to mailer terminated");
-                break;
+
+            let email = message(&self.from, &recipients, mail)?;
+            let sent = match &self.transport {
+                Transport::Smtp(t) => observe("smtp", "send", t.send(email)).await.map(|_| ()).map_err(NotifyError::from),
+                Transport::Sendmail(t) => observe("sendmail", "send", t.send(email)).await.map_err(NotifyError::from),
+                Transport::File(t) => observe("file", "send", t.send(email)).await.map(|_| ()).map_err(NotifyError::from),
+            };
+            METRICS.mails.with_label_values(&[result(sent.is_ok())]).inc();
+
+            sent
+        })
+    }
+}
+
+/// Returns a parsed mailbox, e.g. "Name <name@domain>"
+///
+/// # Arguments
+///
+/// * 'address' - the mailbox to parse
+fn mailbox(address: &str) -> Result<Mailbox, MailError> {
+    address.parse::<Mailbox>().map_err(|e| MailError(format!("invalid mailbox {}: {}", address, e)))
+}
+
+/// A log event in a batch, with the number of times it occurred
+///
+struct Event {
+    key: String,
+    severity: Severity,
+    text: String,
+    count: u32,
+    last: DateTime<Local>,
+}
+
+/// Mails waiting to be sent on a channel, batching and deduplicating log events and limiting the
+/// number of mails sent per hour
+///
+pub struct Outbox {
+    window: Duration,
+    max_per_hour: usize,
+    events: Vec<Event>,
+    omitted: u32,
+    batch_started: Option<Instant>,
+    mails: VecDeque<Mail>,
+    sent: VecDeque<Instant>,
+}
+
+impl Outbox {
+
+    /// Returns an empty outbox
+    ///
+    /// # Arguments
+    ///
+    /// * 'batch_window_secs' - seconds log events are collected into one mail
+    /// * 'max_per_hour' - maximum number of mails sent per hour
+    pub fn new(batch_window_secs: Option<u64>, max_per_hour: Option<usize>) -> Self {
+        Outbox {
+            window: Duration::from_secs(batch_window_secs.unwrap_or(DEFAULT_BATCH_WINDOW_SECS)),
+            max_per_hour: max_per_hour.unwrap_or(DEFAULT_MAX_MAILS_PER_HOUR).max(1),
+            events: Vec::new(),
+            omitted: 0,
+            batch_started: None,
+            mails: VecDeque::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Adds a mail, counting a log event identical to one already in the batch
+    ///
+    /// # Arguments
+    ///
+    /// * 'mail' - the mail to add
+    pub fn push(&mut self, mail: Mail) {
+        let key = match mail.key {
+            Some(key) => key,
+            None => return self.mails.push_back(mail),
+        };
+
+        self.batch_started.get_or_insert_with(Instant::now);
+        if let Some(event) = self.events.iter_mut().find(|e| e.key == key) {
+            event.count += 1;
+            event.last = Local::now();
+        } else if self.events.len() < MAX_BATCH_EVENTS {
+            self.events.push(Event { key, severity: mail.severity, text: mail.text, count: 1, last: Local::now() });
+        } else {
+            self.omitted += 1;
+        }
+    }
+
+    /// Returns the next mail due, if the hourly limit allows sending it
+    ///
+    /// # Arguments
+    ///
+    /// * 'now' - the current time
+    pub fn next(&mut self, now: Instant) -> Option<Mail> {
+        while self.sent.front().is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(3600)) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max_per_hour {
+            return None;
+        }
+
+        let mail = match self.mails.pop_front() {
+            Some(mail) => mail,
+            None if self.batch_started.is_some_and(|t| now >= t + self.window) => self.batch(),
+            None => return None,
+        };
+        self.sent.push_back(now);
+
+        Some(mail)
+    }
+
+    /// Returns when the next mail is due, or None if there is nothing to send
+    ///
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.mails.is_empty() && self.batch_started.is_none() {
+            return None;
+        }
+        if self.sent.len() >= self.max_per_hour {
+            return self.sent.front().map(|t| *t + Duration::from_secs(3600));
+        }
+
+        match self.mails.is_empty() {
+            true => self.batch_started.map(|t| t + self.window),
+            false => Some(Instant::now()),
+        }
+    }
+
+    /// Returns one mail with all log events in the batch and starts a new batch
+    ///
+    fn batch(&mut self) -> Mail {
+        let events = std::mem::take(&mut self.events);
+        let omitted = std::mem::take(&mut self.omitted);
+        self.batch_started = None;
+
+        let count: u32 = events.iter().map(|e| e.count).sum::<u32>() + omitted;
+        let severity = events.iter().map(|e| e.severity).max().unwrap_or_default();
+        let subject = match count {
+            1 => "CloudSync event".to_string(),
+            n => format!("CloudSync events: {}", n),
+        };
+        let mut text = String::new();
+        for e in events {
+            text.push_str(&e.text);
+            if e.count > 1 {
+                text.push_str(&format!("  (repeated {} times, last at {})\n", e.count, e.last.format("%Y-%m-%d %H:%M:%S %:z")));
             }
         }
+        if omitted > 0 {
+            text.push_str(&format!("\n{} further events omitted, see the log\n", omitted));
+        }
+
+        Mail { key: None, severity, kind: "events".to_string(), subject, text, html: None, attachments: Vec::new() }
     }
 }
 
-/// Creates and returns a mail sender
+/// Creates and returns the mail transport given by configuration
+/// SMTP uses implicit TLS (port 465) unless STARTTLS (port 587) or no TLS (port 25) is configured,
+/// and authenticates unless disabled
 ///
 /// # Arguments
 ///
 /// * 'config' - mail configuration parameters
-fn sender(config: &MailParameters) -> Result<AsyncSmtpTransport<Tokio1Executor>, MailError> {
-    let credentials = Credentials::new(config.smtp_user.to_owned(), config.smtp_password.to_owned());
-    let sender: AsyncSmtpTransport<Tokio1Executor> = AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_endpoint)?
-        .credentials(credentials)
-        .build();
+fn transport(config: &MailParameters) -> Result<Transport, MailError> {
+    match config.transport {
+        MailTransport::Smtp => {
+            let mut builder = match config.smtp_tls {
+                SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_endpoint)?,
+                SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_endpoint)?,
+                SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_endpoint),
+            };
+            if let Some(port) = config.smtp_port {
+                builder = builder.port(port);
+            }
+            if config.smtp_auth.unwrap_or(true) {
+                builder = builder.credentials(Credentials::new(config.smtp_user.to_owned(), config.smtp_password.to_owned()));
+            }
 
-    Ok(sender)
+            Ok(Transport::Smtp(builder.build()))
+        },
+        MailTransport::Sendmail => Ok(Transport::Sendmail(match &config.sendmail_command {
+            Some(command) => AsyncSendmailTransport::<Tokio1Executor>::new_with_command(command),
+            None => AsyncSendmailTransport::<Tokio1Executor>::new(),
+        })),
+        MailTransport::File => {
+            let dir = config.file_dir.as_ref().ok_or(MailError("file_dir is required for the file transport".to_string()))?;
+            std::fs::create_dir_all(dir)?;
+
+            Ok(Transport::File(AsyncFileTransport::<Tokio1Executor>::new(dir)))
+        },
+    }
 }
 
-/// Creates a new email message
+/// Creates a new email message, plain text only unless the mail has an HTML version or
+/// attachments
 ///
 /// # Arguments
 ///
 /// * 'from' - from mail address
-/// * 'to' - to mail address
-/// * 'subject' - mail subject
-/// * 'body' - mail body
-fn message(from: &Mailbox, to: &Mailbox, subject: &str, body: String) -> Result<Message, MailError> {
-    Ok(Message::builder()
+/// * 'recipients' - the recipients to send to
+/// * 'mail' - the mail to send
+fn message(from: &Mailbox, recipients: &[&Recipient], mail: &Mail) -> Result<Message, MailError> {
+    let mut builder = Message::builder()
         .from(from.clone())
-        .to(to.clone())
-        .subject(subject)
-        .header(ContentType::TEXT_PLAIN)
-        .body(body)?)
+        .subject(&mail.subject);
+    for r in recipients {
+        builder = match r.cc {
+            true => builder.cc(r.mailbox.clone()),
+            false => builder.to(r.mailbox.clone()),
+        };
+    }
+
+    if mail.html.is_none() && mail.attachments.is_empty() {
+        return Ok(builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.text.clone())?);
+    }
+
+    let body = match &mail.html {
+        Some(html) => MultiPart::alternative_plain_html(mail.text.clone(), html.clone()),
+        None => MultiPart::mixed().singlepart(SinglePart::plain(mail.text.clone())),
+    };
+    let mut multipart = MultiPart::mixed().multipart(body);
+    for attachment in &mail.attachments {
+        let content_type = ContentType::parse(&attachment.content_type)
+            .map_err(|e| MailError(format!("invalid attachment content type: {}", e)))?;
+        multipart = multipart.singlepart(MailAttachment::new(attachment.filename.clone()).body(attachment.content.clone(), content_type));
+    }
+
+    Ok(builder.multipart(multipart)?)
 }
\ No newline at end of file
@@ -0,0 +1,263 @@
+use std::path::PathBuf;
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use crate::errors::MailError;
+use crate::initialization::MailParameters;
+use crate::mail_manager::Mail;
+use crate::metrics::METRICS;
+
+/// Number of attempts to send a mail before it is given to the fallback sink if not configured
+const DEFAULT_MAX_SEND_ATTEMPTS: u32 = 12;
+
+/// Seconds to wait before the first retry, doubled for each further retry
+const RETRY_BASE_SECS: i64 = 30;
+
+/// Longest wait between retries in seconds
+const RETRY_MAX_SECS: i64 = 3600;
+
+/// A mail waiting to be delivered on a notification channel
+#[derive(Serialize, Deserialize)]
+struct Spooled {
+    id: String,
+    #[serde(default = "mail_channel")]
+    channel: String,
+    mail: Mail,
+    attempts: u32,
+    next_attempt: DateTime<Utc>,
+}
+
+/// Returns the channel of mails spooled before there were several channels
+///
+fn mail_channel() -> String {
+    "mail".to_string()
+}
+
+/// Outgoing mails kept until delivered, one JSON file per mail in the spool directory so that
+/// mails not yet delivered survive a restart. Failed mails are retried with backoff, and after
+/// the maximum number of attempts given to the fallback sink: appended to the fallback file if
+/// configured, otherwise written to the log
+///
+pub struct Spool {
+    dir: Option<PathBuf>,
+    fallback_path: Option<PathBuf>,
+    max_attempts: u32,
+    mails: Vec<Spooled>,
+    sequence: u32,
+}
+
+impl Spool {
+
+    /// Returns the spool given by configuration, with any mails left in the spool directory
+    /// queued for immediate delivery
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - mail configuration parameters
+    pub async fn load(config: &MailParameters) -> Self {
+        let mut spool = Spool {
+            dir: config.spool_dir.as_ref().map(PathBuf::from),
+            fallback_path: config.fallback_path.as_ref().map(PathBuf::from),
+            max_attempts: config.max_send_attempts.unwrap_or(DEFAULT_MAX_SEND_ATTEMPTS).max(1),
+            mails: Vec::new(),
+            sequence: 0,
+        };
+
+        if let Err(e) = spool.read().await {
+            error!("failed to read mail spool: {}", e);
+        }
+        if !spool.mails.is_empty() {
+            info!("flushing {} spooled mails", spool.mails.len());
+        }
+
+        spool
+    }
+
+    /// Adds a mail to the spool, due for delivery now
+    ///
+    /// # Arguments
+    ///
+    /// * 'channel' - name of the channel to deliver on
+    /// * 'mail' - the mail to deliver
+    pub async fn add(&mut self, channel: &str, mail: Mail) {
+        self.sequence = self.sequence.wrapping_add(1);
+        let now = Utc::now();
+        let spooled = Spooled {
+            id: format!("{}-{:05}", now.format("%Y%m%dT%H%M%S%.6fZ"), self.sequence),
+            channel: channel.to_string(),
+            mail,
+            attempts: 0,
+            next_attempt: now,
+        };
+
+        if let Err(e) = self.write(&spooled).await {
+            error!("failed to spool mail: {}", e);
+        }
+        self.mails.push(spooled);
+    }
+
+    /// Returns the ids of mails due for delivery
+    ///
+    pub fn due(&self) -> Vec<String> {
+        let now = Utc::now();
+        self.mails.iter()
+            .filter(|s| s.next_attempt <= now)
+            .map(|s| s.id.clone())
+            .collect()
+    }
+
+    /// Returns a mail in the spool with the channel to deliver it on
+    ///
+    /// # Arguments
+    ///
+    /// * 'id' - id of the spooled mail
+    pub fn get(&self, id: &str) -> Option<(&str, &Mail)> {
+        self.mails.iter().find(|s| s.id == id).map(|s| (s.channel.as_str(), &s.mail))
+    }
+
+    /// Returns when the next mail is due, or None if the spool is empty
+    ///
+    pub fn deadline(&self) -> Option<Instant> {
+        let next = self.mails.iter().map(|s| s.next_attempt).min()?;
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+
+        Some(Instant::now() + wait)
+    }
+
+    /// Removes a delivered mail from the spool
+    ///
+    /// # Arguments
+    ///
+    /// * 'id' - id of the spooled mail
+    pub async fn delivered(&mut self, id: &str) {
+        self.mails.retain(|s| s.id != id);
+        if let Err(e) = self.remove(id).await {
+            error!("failed to remove mail from spool: {}", e);
+        }
+    }
+
+    /// Schedules a retry of a mail that failed to send, or gives it to the fallback sink when
+    /// it has used all attempts
+    ///
+    /// # Arguments
+    ///
+    /// * 'id' - id of the spooled mail
+    /// * 'reason' - why sending failed
+    pub async fn failed(&mut self, id: &str, reason: &str) {
+        let index = match self.mails.iter().position(|s| s.id == id) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let spooled = &mut self.mails[index];
+        spooled.attempts += 1;
+        if spooled.attempts < self.max_attempts {
+            let wait = (RETRY_BASE_SECS << (spooled.attempts - 1).min(16)).min(RETRY_MAX_SECS);
+            spooled.next_attempt = Utc::now() + TimeDelta::seconds(wait);
+            warn!("sending {} {} failed, attempt {} of {}, retrying in {} s: {}", spooled.channel, id, spooled.attempts, self.max_attempts, wait, reason);
+            METRICS.retries.with_label_values(&["mail"]).inc();
+            let spooled = &self.mails[index];
+            if let Err(e) = self.write(spooled).await {
+                error!("failed to update spooled mail: {}", e);
+            }
+            return;
+        }
+
+        let spooled = self.mails.remove(index);
+        error!("giving up sending {} {} after {} attempts: {}", spooled.channel, id, spooled.attempts, reason);
+        METRICS.mails.with_label_values(&["fallback"]).inc();
+        if let Err(e) = self.fallback(&spooled).await {
+            error!("fallback failed, mail lost: {}\n{}\n{}", e, spooled.mail.subject, spooled.mail.text);
+        }
+        if let Err(e) = self.remove(id).await {
+            error!("failed to remove mail from spool: {}", e);
+        }
+    }
+
+    /// Gives a mail that couldn't be sent to the fallback sink
+    ///
+    /// # Arguments
+    ///
+    /// * 'spooled' - the spooled mail
+    async fn fallback(&self, spooled: &Spooled) -> Result<(), MailError> {
+        let path = match &self.fallback_path {
+            Some(path) => path,
+            None => {
+                error!("undelivered mail: {}\n{}", spooled.mail.subject, spooled.mail.text);
+                return Ok(());
+            },
+        };
+
+        let entry = format!("===== {} {} {}\n{}\n", Local::now().format("%Y-%m-%d %H:%M:%S %:z"), spooled.channel, spooled.mail.subject, spooled.mail.text);
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        file.write_all(entry.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    /// Reads all mails in the spool directory
+    ///
+    async fn read(&mut self) -> Result<(), MailError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|e| e == "json") {
+                let json = tokio::fs::read_to_string(entry.path()).await?;
+                match serde_json::from_str::<Spooled>(&json) {
+                    Ok(mut spooled) => {
+                        spooled.next_attempt = Utc::now();
+                        self.mails.push(spooled);
+                    },
+                    Err(e) => error!("invalid spooled mail {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+        self.mails.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(())
+    }
+
+    /// Writes a mail to the spool directory, replacing any earlier version
+    ///
+    /// # Arguments
+    ///
+    /// * 'spooled' - the spooled mail
+    async fn write(&self, spooled: &Spooled) -> Result<(), MailError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        tokio::fs::create_dir_all(dir).await?;
+        let tmp = dir.join(format!("{}.tmp", spooled.id));
+        tokio::fs::write(&tmp, serde_json::to_string(spooled)?).await?;
+        tokio::fs::rename(&tmp, dir.join(format!("{}.json", spooled.id))).await?;
+
+        Ok(())
+    }
+
+    /// Removes a mail from the spool directory
+    ///
+    /// # Arguments
+    ///
+    /// * 'id' - id of the spooled mail
+    async fn remove(&self, id: &str) -> Result<(), MailError> {
+        if let Some(dir) = &self.dir {
+            let path = dir.join(format!("{}.json", id));
+            if path.exists() {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -7,27 +7,55 @@ mod onedrive_model;
 mod aws_manager;
 mod chunk;
 mod mail_manager;
+mod mail_spool;
+mod notifier;
 mod logging;
+mod storage_class;
+mod crypto;
+mod transform;
+mod restore;
+mod object_key;
+mod manifest;
+mod compression;
+mod migrate;
+mod content_hash;
+mod scrub;
+mod metrics;
+mod status;
+mod history;
+mod report;
 
 use log::{error, info};
 use std::sync::Arc;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
-use axum::extract::{Query, State};
+use axum::extract::{Path, Query, State};
 use axum::http::{header, StatusCode};
-use axum::response::{IntoResponse, Redirect};
+use axum::response::{Html, IntoResponse, Json, Redirect};
 use axum::Router;
 use axum::routing::get;
 use reqwest::Url;
 use serde::Deserialize;
 use tokio::sync::mpsc;
-use crate::initialization::{config, Config, OneDrive};
+use crate::initialization::{argument, config, Config, OneDrive};
 use crate::errors::UnrecoverableError;
 use crate::cloud_sync::sync;
-use crate::mail_manager::mailer;
+use crate::mail_manager::{Mail, MAIL_CHANNEL_CAPACITY};
+use crate::notifier::notifier;
+use crate::metrics::METRICS;
+use crate::migrate::migrate_layout;
+use crate::history::{history_page, History};
+use crate::status::{dashboard, SyncState, SyncStatus};
+use crate::restore::{list, restore};
 use crate::token_manager::Tokens;
 
-pub type SharedState = Arc<Config>;
+/// State shared between the sync and the web server
+pub struct AppState {
+    pub config: Config,
+    pub status: SyncStatus,
+}
+
+pub type SharedState = Arc<AppState>;
 
 #[derive(Deserialize)]
 struct Params {
@@ -35,44 +63,119 @@ struct Params {
 }
 
 async fn code(State(state): State<SharedState>, Query(params): Query<Params>) -> impl IntoResponse {
-    if let Err(e) = Tokens::from_code(&state.onedrive, &params.code).await {
-        (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], e.to_string())
-            .into_response()
+    match Tokens::from_code(&state.config.onedrive, &params.code).await {
+        Ok(tokens) => {
+            state.status.tokens(&tokens, &state.config.onedrive);
+            state.status.update(|s| if matches!(s.state, SyncState::Paused) { s.state = SyncState::Sleeping });
+            ([(header::CONTENT_TYPE, "text/plain")], "Access granted!")
+                .into_response()
+        },
+        Err(e) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], e.to_string())
+                .into_response()
+        },
+    }
+}
 
-    } else {
-        ([(header::CONTENT_TYPE, "text/plain")], "Access granted!")
-            .into_response()
+async fn status(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.status.snapshot())
+}
+
+async fn status_page(State(state): State<SharedState>) -> impl IntoResponse {
+    Html(dashboard(&state.status.snapshot()))
+}
+
+async fn metrics() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], METRICS.encode())
+}
+
+async fn history(State(state): State<SharedState>) -> impl IntoResponse {
+    match History::new(&state.config.general).runs().await {
+        Ok(runs) => Html(history_page(&runs)).into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, [(header::CONTENT_TYPE, "text/plain")], e.to_string()).into_response(),
+    }
+}
+
+async fn history_run(State(state): State<SharedState>, Path(id): Path<String>) -> impl IntoResponse {
+    match History::new(&state.config.general).run(&id).await {
+        Ok(Some(run)) => Json(run).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, [(header::CONTENT_TYPE, "text/plain")], "run not found".to_string()).into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, [(header::CONTENT_TYPE, "text/plain")], e.to_string()).into_response(),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), UnrecoverableError> {
     // Load configuration
-    let (tx, rx) = mpsc::unbounded_channel::<String>();
-    let config: SharedState = Arc::new(config(tx)?);
+    let (tx, rx) = mpsc::channel::<Mail>(MAIL_CHANNEL_CAPACITY);
+    let config = config(tx.clone())?;
+
+    // Restore objects instead of running as a service if requested
+    if let Some(prefix) = argument("restore") {
+        let dir = argument("restore-dir").unwrap_or(".".to_string());
+        info!("restoring {} to {}", prefix, dir);
+        restore(&config, &prefix, &dir).await?;
+        return Ok(());
+    }
+
+    // List backed up paths instead of running as a service if requested
+    if let Some(prefix) = argument("list") {
+        list(&config, &prefix).await?;
+        return Ok(());
+    }
+
+    // Migrate objects to the configured key layout instead of running as a service if requested
+    if let Some(old_prefix) = argument("migrate-layout") {
+        let delete_old = argument("delete-old").is_some_and(|d| d == "true");
+        migrate_layout(&config, &old_prefix, delete_old).await?;
+        return Ok(());
+    }
+
+    // Print the run history instead of running as a service if requested
+    if let Some(count) = argument("history") {
+        let count = count.parse::<usize>().unwrap_or(usize::MAX);
+        for run in History::new(&config.general).runs().await?.iter().take(count) {
+            println!("{}", run.summary());
+        }
+        return Ok(());
+    }
+    if let Some(id) = argument("history-run") {
+        match History::new(&config.general).run(&id).await? {
+            Some(run) => println!("{}", serde_json::to_string_pretty(&run).unwrap_or_default()),
+            None => println!("run not found: {}", id),
+        }
+        return Ok(());
+    }
      
-    // Mailer
-    info!("starting mailer");
-    let c = config.clone();
-    tokio::spawn(async move { mailer(&c.mail, rx).await });
+    let state: SharedState = Arc::new(AppState { config, status: SyncStatus::default() });
+
+    // Notifier
+    info!("starting notifier");
+    let s = state.clone();
+    tokio::spawn(async move { notifier(&s.config, rx).await });
 
     // Main sync function
     info!("starting main sync function");
-    let c = config.clone();
-    tokio::spawn(async move { sync(&c).await });
+    let s = state.clone();
+    tokio::spawn(async move { sync(&s.config, &s.status, &tx).await });
 
     // Authentication/authorization function
     info!("starting authentication/authorization function");
 
-    let redirect_url = build_access_request_url(&config.onedrive);
+    let redirect_url = build_access_request_url(&state.config.onedrive);
 
     let app = Router::new()
         .route("/code", get(code))
         .route("/grant", get(|| async move { Redirect::to(&redirect_url) }))
-        .with_state(config.clone());
+        .route("/metrics", get(metrics))
+        .route("/status", get(status))
+        .route("/dashboard", get(status_page))
+        .route("/history", get(history))
+        .route("/history/{id}", get(history_run))
+        .with_state(state.clone());
 
-    let ip_addr = Ipv4Addr::from_str(&config.web_server.bind_address).expect("invalid BIND_ADDR");
-    let addr = SocketAddr::new(IpAddr::V4(ip_addr), config.web_server.bind_port);
+    let ip_addr = Ipv4Addr::from_str(&state.config.web_server.bind_address).expect("invalid BIND_ADDR");
+    let addr = SocketAddr::new(IpAddr::V4(ip_addr), state.config.web_server.bind_port);
 
     let result = axum_server::bind(addr)
         .serve(app.into_make_service())
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use crate::aws_manager::{ObjectInfo, AWS};
+use crate::crypto::MasterKey;
+use crate::errors::ManifestError;
+use crate::transform::UploadTransform;
+
+/// Object key of the manifest copy kept in the bucket
+pub const MANIFEST_KEY: &str = ".cloud_sync/manifest.json";
+
+/// Object key prefix for content addressed blobs when deduplication is enabled
+pub const BLOB_PREFIX: &str = ".cloud_sync/blobs/";
+
+/// Object key prefix for objects used internally by cloud_sync
+pub const INTERNAL_PREFIX: &str = ".cloud_sync/";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub item_id: String,
+    #[serde(default)]
+    pub blob: Option<String>,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub created: Option<i64>,
+}
+
+impl ManifestEntry {
+
+    /// Returns an entry rebuilt from the metadata of a stored object, for objects the manifest
+    /// has no entry for
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path of the file the object holds
+    /// * 'info' - object information of the stored object
+    pub fn from_object_info(path: String, info: &ObjectInfo) -> Self {
+        let blob = info.metadata.get("blob").cloned();
+
+        ManifestEntry {
+            path,
+            item_id: info.metadata.get("onedrive-id").cloned().unwrap_or_default(),
+            checksum: if blob.is_none() { info.checksum.clone() } else { None },
+            blob,
+            created: info.metadata.get("created").and_then(|c| c.parse::<i64>().ok()),
+        }
+    }
+}
+
+/// Mapping between S3 object keys and the OneDrive items they hold
+/// It also keeps track of the casing each folder was first seen with, since OneDrive is
+/// case-insensitive while object keys are not
+///
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    entries: HashMap<String, ManifestEntry>,
+    #[serde(skip)]
+    items: HashMap<String, String>,
+    #[serde(skip)]
+    folders: HashMap<String, String>,
+    #[serde(skip)]
+    blobs: HashMap<String, Option<String>>,
+}
+
+impl Manifest {
+
+    /// Loads a manifest from file, or returns an empty manifest if there is no file
+    ///
+    /// # Arguments
+    ///
+    /// * 'manifest_path' - path to the manifest file
+    pub async fn from_file(manifest_path: &str) -> Result<Self, ManifestError> {
+        if Path::new(manifest_path).exists() {
+            let json = tokio::fs::read(manifest_path).await?;
+            Self::from_bytes(&json)
+        } else {
+            Ok(Manifest::default())
+        }
+    }
+
+    /// Creates a manifest from its json representation
+    ///
+    /// # Arguments
+    ///
+    /// * 'json' - manifest as json
+    pub fn from_bytes(json: &[u8]) -> Result<Self, ManifestError> {
+        let mut manifest: Manifest = serde_json::from_slice(json)?;
+
+        // Paths are indexed in order so that the folder casing picked is the same every time
+        let mut entries: Vec<(&String, &ManifestEntry)> = manifest.entries.iter().collect();
+        entries.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+        let mut items: HashMap<String, String> = HashMap::new();
+        let mut folders: HashMap<String, String> = HashMap::new();
+        let mut blobs: HashMap<String, Option<String>> = HashMap::new();
+        for (object_name, entry) in entries {
+            items.insert(entry.item_id.clone(), object_name.clone());
+            Self::add_folders(&mut folders, &entry.path);
+            if let Some(blob) = &entry.blob {
+                blobs.insert(blob.clone(), entry.checksum.clone());
+            }
+        }
+        manifest.items = items;
+        manifest.folders = folders;
+        manifest.blobs = blobs;
+
+        Ok(manifest)
+    }
+
+    /// Returns the json representation of the manifest
+    ///
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ManifestError> {
+        Ok(serde_json::to_vec(&self)?)
+    }
+
+    /// Saves the manifest to file
+    ///
+    /// # Arguments
+    ///
+    /// * 'manifest_path' - path to the manifest file
+    pub async fn save(&self, manifest_path: &str) -> Result<(), ManifestError> {
+        tokio::fs::write(manifest_path, self.to_bytes()?).await?;
+
+        Ok(())
+    }
+
+    /// Stores a copy of the manifest in the bucket, encrypted if a master key is given
+    ///
+    /// # Arguments
+    ///
+    /// * 'aws' - the AWS manager
+    /// * 'master_key' - master key if client side encryption is enabled
+    pub async fn upload(&self, aws: &AWS, master_key: &Option<MasterKey>) -> Result<(), ManifestError> {
+        let mut transform = UploadTransform::new(master_key, None)?;
+        let mut content = transform.update(self.to_bytes()?)?;
+        content.extend(transform.finish()?);
+
+        let content_type = Some("application/octet-stream".to_string());
+        aws.put_object(MANIFEST_KEY, &content_type, transform.metadata(), &None, content).await?;
+
+        Ok(())
+    }
+
+    /// Adds or replaces the entry for an object key
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - the object key
+    /// * 'entry' - the manifest entry
+    pub fn insert(&mut self, object_name: &str, entry: ManifestEntry) {
+        self.items.insert(entry.item_id.clone(), object_name.to_string());
+        Self::add_folders(&mut self.folders, &entry.path);
+        if let Some(blob) = &entry.blob {
+            self.blobs.insert(blob.clone(), entry.checksum.clone());
+        }
+        self.entries.insert(object_name.to_string(), entry);
+    }
+
+    /// Removes the entry for an object key
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - the object key
+    pub fn remove(&mut self, object_name: &str) {
+        if let Some(entry) = self.entries.remove(object_name) {
+            if self.items.get(&entry.item_id).is_some_and(|k| k == object_name) {
+                self.items.remove(&entry.item_id);
+            }
+        }
+    }
+
+    /// Returns the object key and entry last recorded for a OneDrive item
+    ///
+    /// # Arguments
+    ///
+    /// * 'item_id' - OneDrive item id
+    pub fn find_item(&self, item_id: &str) -> Option<(&String, &ManifestEntry)> {
+        self.items.get(item_id).and_then(|k| self.entries.get_key_value(k))
+    }
+
+    /// Returns the checksum last recorded for a deduplicated blob, where None means that the
+    /// blob isn't referred to by any entry and Some(None) that it was stored without a checksum
+    ///
+    /// # Arguments
+    ///
+    /// * 'blob' - object key of the blob
+    pub fn blob_checksum(&self, blob: &str) -> Option<&Option<String>> {
+        self.blobs.get(blob)
+    }
+
+    /// Returns a path where the folder part is given the casing the folder was first seen
+    /// with, so that objects in a folder stay under the same key prefix even if the folder
+    /// casing changes on OneDrive
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path
+    pub fn canonical_path(&self, path: &str) -> String {
+        match path.rsplit_once('/') {
+            Some((folder, name)) => match self.folders.get(&folder.to_lowercase()) {
+                Some(folder) => format!("{}/{}", folder, name),
+                None => path.to_string(),
+            },
+            None => path.to_string(),
+        }
+    }
+
+    /// Adds all folders of a path not already known, in the casing of known parent folders
+    ///
+    /// # Arguments
+    ///
+    /// * 'folders' - known folders by lowercase path
+    /// * 'path' - OneDrive path
+    fn add_folders(folders: &mut HashMap<String, String>, path: &str) {
+        let mut canonical = String::new();
+        if let Some((folder, _)) = path.rsplit_once('/') {
+            for component in folder.split('/') {
+                if !canonical.is_empty() {
+                    canonical.push('/');
+                }
+                canonical.push_str(component);
+                canonical = folders.entry(canonical.to_lowercase()).or_insert(canonical).clone();
+            }
+        }
+    }
+
+    /// Returns the entry for an object key
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - the object key
+    pub fn get(&self, object_name: &str) -> Option<&ManifestEntry> {
+        self.entries.get(object_name)
+    }
+
+    /// Returns object keys and entries with a path starting with the given prefix,
+    /// sorted by path
+    /// Paths are compared Unicode normalized (NFC), since the same name may be stored
+    /// differently depending on where the file was created
+    ///
+    /// # Arguments
+    ///
+    /// * 'prefix' - path prefix
+    pub fn find(&self, prefix: &str) -> Vec<(&String, &ManifestEntry)> {
+        let prefix: String = prefix.nfc().collect();
+        let mut found: Vec<(&String, &ManifestEntry)> = self.entries.iter()
+            .filter(|(_, e)| e.path.nfc().collect::<String>().starts_with(&prefix))
+            .collect();
+        found.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+
+        found
+    }
+}
@@ -0,0 +1,148 @@
+use std::future::Future;
+use std::sync::LazyLock;
+use prometheus::{exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tokio::time::Instant;
+
+/// Metrics of the running service, exposed in the Prometheus text format on the /metrics route
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Counters and histograms for sync runs, OneDrive and AWS requests, retries and mail
+///
+pub struct Metrics {
+    registry: Registry,
+    pub runs: IntCounterVec,
+    pub run_duration: Histogram,
+    pub last_success: IntGauge,
+    pub files: IntCounterVec,
+    pub bytes: IntCounterVec,
+    pub errors: IntCounterVec,
+    pub token_refreshes: IntCounterVec,
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    pub retries: IntCounterVec,
+    pub mails: IntCounterVec,
+}
+
+impl Metrics {
+
+    /// Returns all metrics registered in a registry of their own
+    /// Metric names and labels are fixed, so registration can only fail by a programming error
+    ///
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let runs = IntCounterVec::new(
+            Opts::new("cloudsync_runs_total", "Sync runs by result"), &["result"]).unwrap();
+        let run_duration = Histogram::with_opts(
+            HistogramOpts::new("cloudsync_run_duration_seconds", "Duration of completed sync runs")
+                .buckets(exponential_buckets(1.0, 4.0, 10).unwrap())).unwrap();
+        let last_success = IntGauge::new(
+            "cloudsync_last_success_timestamp_seconds", "Time the last sync run completed").unwrap();
+        let files = IntCounterVec::new(
+            Opts::new("cloudsync_files_total", "Files stored by action"), &["action"]).unwrap();
+        let bytes = IntCounterVec::new(
+            Opts::new("cloudsync_bytes_total", "Bytes transferred by service and direction"), &["service", "direction"]).unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("cloudsync_errors_total", "Failed sync runs by error type"), &["type"]).unwrap();
+        let token_refreshes = IntCounterVec::new(
+            Opts::new("cloudsync_token_refreshes_total", "OneDrive token refreshes by result"), &["result"]).unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("cloudsync_requests_total", "Requests by service, operation and result"), &["service", "operation", "result"]).unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("cloudsync_request_duration_seconds", "Duration of requests by service and operation")
+                .buckets(exponential_buckets(0.01, 4.0, 9).unwrap()), &["service", "operation"]).unwrap();
+        let retries = IntCounterVec::new(
+            Opts::new("cloudsync_retries_total", "Retries by reason"), &["reason"]).unwrap();
+        let mails = IntCounterVec::new(
+            Opts::new("cloudsync_mails_total", "Mails by result"), &["result"]).unwrap();
+
+        registry.register(Box::new(runs.clone())).unwrap();
+        registry.register(Box::new(run_duration.clone())).unwrap();
+        registry.register(Box::new(last_success.clone())).unwrap();
+        registry.register(Box::new(files.clone())).unwrap();
+        registry.register(Box::new(bytes.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(token_refreshes.clone())).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(retries.clone())).unwrap();
+        registry.register(Box::new(mails.clone())).unwrap();
+
+        Metrics {
+            registry,
+            runs,
+            run_duration,
+            last_success,
+            files,
+            bytes,
+            errors,
+            token_refreshes,
+            requests,
+            request_duration,
+            retries,
+            mails,
+        }
+    }
+
+    /// Returns all metrics in the Prometheus text format
+    ///
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+
+    /// Records a completed request
+    ///
+    /// # Arguments
+    ///
+    /// * 'service' - the service requested, e.g. "aws"
+    /// * 'operation' - the operation requested, e.g. "put_object"
+    /// * 'start' - when the request was sent
+    /// * 'ok' - whether the request succeeded
+    pub fn request(&self, service: &str, operation: &str, start: Instant, ok: bool) {
+        self.request_duration.with_label_values(&[service, operation]).observe(start.elapsed().as_secs_f64());
+        self.requests.with_label_values(&[service, operation, result(ok)]).inc();
+    }
+}
+
+/// Awaits a request and records its duration and result
+///
+/// # Arguments
+///
+/// * 'service' - the service requested, e.g. "aws"
+/// * 'operation' - the operation requested, e.g. "put_object"
+/// * 'request' - the request future
+pub async fn observe<T, E>(service: &str, operation: &str, request: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let start = Instant::now();
+    let response = request.await;
+    METRICS.request(service, operation, start, response.is_ok());
+
+    response
+}
+
+/// Awaits an HTTP request and records its duration and result, where responses with other
+/// than a success or redirection status count as failed
+///
+/// # Arguments
+///
+/// * 'service' - the service requested, e.g. "onedrive"
+/// * 'operation' - the operation requested, e.g. "get_delta"
+/// * 'request' - the request future
+pub async fn observe_http(service: &str, operation: &str, request: impl Future<Output = Result<reqwest::Response, reqwest::Error>>) -> Result<reqwest::Response, reqwest::Error> {
+    let start = Instant::now();
+    let response = request.await;
+    let ok = response.as_ref().is_ok_and(|r| r.status().is_success() || r.status().is_redirection());
+    METRICS.request(service, operation, start, ok);
+
+    response
+}
+
+/// Returns the result label for an outcome
+///
+/// # Arguments
+///
+/// * 'ok' - whether the outcome was successful
+pub fn result(ok: bool) -> &'static str {
+    if ok { "ok" } else { "error" }
+}
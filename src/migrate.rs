@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet};
+use log::{error, info};
+use percent_encoding::percent_decode_str;
+use crate::aws_manager::{ObjectInfo, AWS};
+use crate::crypto::MasterKey;
+use crate::errors::MigrationError;
+use crate::initialization::Config;
+use crate::manifest::{Manifest, ManifestEntry, INTERNAL_PREFIX, MANIFEST_KEY};
+use crate::object_key::KeyMapper;
+
+/// Migrates objects from the layout recorded in the manifest to the configured key template
+/// and key prefix
+/// Objects are copied within the bucket, keeping metadata and storage class, and the manifest
+/// is updated with the new object keys. Objects in the old layout are only deleted if requested
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'old_prefix' - key prefix the objects are currently stored under
+/// * 'delete_old' - whether to delete objects in the old layout once copied
+pub async fn migrate_layout(config: &Config, old_prefix: &str, delete_old: bool) -> Result<(), MigrationError> {
+    let aws = AWS::new(&config.aws).await?;
+    let mut old_config = config.aws.clone();
+    old_config.key_prefix = Some(old_prefix.to_string());
+    let source = AWS::new(&old_config).await?;
+    let master_key = if config.aws.client_side_encryption {
+        Some(MasterKey::new(&config.aws.cse_master_key)?)
+    } else {
+        None
+    };
+    let keys = KeyMapper::new(&master_key, &config.aws)?;
+
+    // Objects of files unchanged since the manifest was introduced have no entry in it, so
+    // the old prefix is also listed and entries are rebuilt for any objects not in the manifest
+    let mut manifest = Manifest::from_file(&config.manifest_path()).await?;
+    let new_prefix = aws.prefix().strip_prefix(source.prefix()).unwrap_or_default();
+    let listed = source.list_objects("").await?;
+    let mut found = 0;
+    for object_name in unlisted_objects(&manifest, listed, new_prefix) {
+        if let Some(info) = source.get_object_info(&object_name).await? {
+            let path = stored_path(&keys, &object_name, &info)?;
+            manifest.insert(&object_name, ManifestEntry::from_object_info(path, &info));
+            found += 1;
+        }
+    }
+    if found > 0 {
+        info!("found {} objects not in the manifest", found);
+    }
+
+    let entries = manifest.find("");
+    if entries.is_empty() {
+        return Err(MigrationError("manifest is empty, nothing to migrate".to_string()));
+    }
+
+    let same_prefix = source.prefix() == aws.prefix();
+    let mut migrated = Manifest::default();
+    let mut copied = 0;
+    let mut failed = 0;
+
+    // Deduplicated blobs don't follow the key template, they only move with the key prefix
+    let mut blob_checksums: HashMap<&String, Option<String>> = HashMap::new();
+    if !same_prefix {
+        let blobs: HashSet<&String> = entries.iter().filter_map(|(_, e)| e.blob.as_ref()).collect();
+        for blob in blobs {
+            match move_object(&aws, &source, blob, blob, delete_old).await {
+                Ok(_) => {
+                    blob_checksums.insert(blob, aws.get_object_info(blob).await?.and_then(|i| i.checksum));
+                    copied += 1;
+                },
+                Err(e) => {
+                    error!("failed to migrate blob {}: {}", blob, e);
+                    failed += 1;
+                },
+            }
+        }
+    }
+
+    for (object_name, entry) in entries {
+        let mut new_name = object_name.clone();
+        let mut new_entry = entry.clone();
+        match migrate_object(&aws, &source, &keys, object_name, entry, delete_old).await {
+            Ok(Some((name, checksum))) => {
+                new_name = name;
+                if entry.blob.is_none() {
+                    new_entry.checksum = checksum;
+                }
+                copied += 1;
+            },
+            Ok(None) => {},
+            Err(e) => {
+                error!("failed to migrate {}: {}", entry.path, e);
+                failed += 1;
+            },
+        }
+        if let Some(checksum) = entry.blob.as_ref().and_then(|b| blob_checksums.get(b)) {
+            new_entry.checksum = checksum.clone();
+        }
+        migrated.insert(&new_name, new_entry);
+    }
+
+    migrated.save(&config.manifest_path()).await?;
+    if !keys.is_plain() {
+        migrated.upload(&aws, &master_key).await?;
+    }
+    if delete_old && !same_prefix && source.get_object_info(MANIFEST_KEY).await?.is_some() {
+        source.delete_object(MANIFEST_KEY).await?;
+    }
+
+    info!("migration done! Copied: {}, Failed: {}", copied, failed);
+    println!("copied: {}, failed: {}", copied, failed);
+
+    Ok(())
+}
+
+/// Migrates one object to the key given by the configured key template
+///
+/// It returns the new object name and the checksum of the copy, or None if the object is
+/// already in place
+///
+/// # Arguments
+///
+/// * 'aws' - AWS manager for the configured key prefix
+/// * 'source' - AWS manager for the old key prefix
+/// * 'keys' - key mapper for the configured key template
+/// * 'object_name' - current name and path of the object
+/// * 'entry' - manifest entry of the file the object holds
+/// * 'delete_old' - whether to delete the object in the old layout once copied
+async fn migrate_object(aws: &AWS, source: &AWS, keys: &KeyMapper, object_name: &str, entry: &ManifestEntry, delete_old: bool) -> Result<Option<(String, Option<String>)>, MigrationError> {
+    let info = source.get_object_info(object_name).await?
+        .ok_or(MigrationError(format!("object not found: {}", object_name)))?;
+
+    let new_name = new_key(keys, entry, &info);
+    if new_name == object_name && source.prefix() == aws.prefix() {
+        return Ok(None);
+    }
+
+    move_object(aws, source, object_name, &new_name, delete_old).await?;
+
+    // A copy gets a checksum of its own, which may be of another type than the original
+    let checksum = aws.get_object_info(&new_name).await?.and_then(|i| i.checksum);
+
+    Ok(Some((new_name, checksum)))
+}
+
+/// Returns the key of an object in the configured layout
+///
+/// # Arguments
+///
+/// * 'keys' - key mapper for the configured key template
+/// * 'entry' - manifest entry of the file the object holds
+/// * 'info' - object information of the stored object
+fn new_key(keys: &KeyMapper, entry: &ManifestEntry, info: &ObjectInfo) -> String {
+    let created = entry.created
+        .or_else(|| info.metadata.get("created").and_then(|c| c.parse::<i64>().ok()))
+        .or_else(|| info.metadata.get("fs-created").and_then(|c| c.parse::<i64>().ok()))
+        .or(info.mtime)
+        .unwrap_or_default();
+
+    keys.object_key(&entry.path, created)
+}
+
+/// Returns the objects listed under the old prefix that the manifest has no entry for,
+/// leaving out objects used internally by cloud_sync and, if the new prefix is below the old
+/// one, objects already stored under the new prefix
+///
+/// # Arguments
+///
+/// * 'manifest' - the manifest
+/// * 'listed' - object names listed under the old prefix
+/// * 'new_prefix' - the new prefix relative to the old one, empty if not below it
+fn unlisted_objects(manifest: &Manifest, listed: Vec<String>, new_prefix: &str) -> Vec<String> {
+    listed.into_iter()
+        .filter(|o| !o.starts_with(INTERNAL_PREFIX))
+        .filter(|o| new_prefix.is_empty() || !o.starts_with(new_prefix))
+        .filter(|o| manifest.get(o).is_none())
+        .collect()
+}
+
+/// Returns the OneDrive path of a stored object not in the manifest, from the (sealed) path in
+/// its metadata or else its object key, since objects without a path in their metadata are
+/// stored under the path as is
+///
+/// # Arguments
+///
+/// * 'keys' - key mapper for the configured key template
+/// * 'object_name' - name and path of the object
+/// * 'info' - object information of the stored object
+fn stored_path(keys: &KeyMapper, object_name: &str, info: &ObjectInfo) -> Result<String, MigrationError> {
+    match (info.metadata.get("cse-path"), info.metadata.get("path")) {
+        (Some(sealed_path), _) => Ok(keys.open_path(sealed_path)?),
+        (None, Some(path)) => Ok(percent_decode_str(path).decode_utf8_lossy().to_string()),
+        (None, None) => Ok(object_name.to_string()),
+    }
+}
+
+/// Copies an object to its new name and optionally deletes the original
+///
+/// # Arguments
+///
+/// * 'aws' - AWS manager for the configured key prefix
+/// * 'source' - AWS manager for the old key prefix
+/// * 'source_name' - current name and path of the object
+/// * 'object_name' - new name and path of the object
+/// * 'delete_old' - whether to delete the original once copied
+async fn move_object(aws: &AWS, source: &AWS, source_name: &str, object_name: &str, delete_old: bool) -> Result<(), MigrationError> {
+    aws.copy_object(source, source_name, object_name, None).await?;
+    if delete_old {
+        source.delete_object(source_name).await?;
+    }
+    info!("migrated {}{} to {}{}", source.prefix(), source_name, aws.prefix(), object_name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::initialization::AWS as AWSConfig;
+
+    fn object_info(metadata: &[(&str, &str)]) -> ObjectInfo {
+        ObjectInfo {
+            mtime: Some(1_700_000_000),
+            size: Some(3),
+            checksum: Some("CRC32C:AAAAAA==".to_string()),
+            content_length: Some(3),
+            content_type: None,
+            storage_class: None,
+            archive_status: None,
+            restore: None,
+            metadata: metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    fn key_mapper(template: &str) -> KeyMapper {
+        let config: AWSConfig = toml::from_str(&format!("region = \"eu-north-1\"\nbucket = \"backup\"\nkey_template = \"{}\"\nprofile = \"alice\"", template)).unwrap();
+
+        KeyMapper::new(&None, &config).unwrap()
+    }
+
+    #[test]
+    fn migrates_object_missing_from_manifest() {
+        let mut manifest = Manifest::default();
+        let known = ManifestEntry::from_object_info("Documents/known.txt".to_string(), &object_info(&[("onedrive-id", "A1")]));
+        manifest.insert("Documents/known.txt", known);
+
+        // An object stored by a version without manifest, with only mtime metadata
+        let listed = vec![
+            "Documents/known.txt".to_string(),
+            "Documents/old [1].txt".to_string(),
+            ".cloud_sync/manifest.json".to_string(),
+            "alice/Documents/known.txt".to_string(),
+        ];
+        let unlisted = unlisted_objects(&manifest, listed, "alice/");
+        assert_eq!(unlisted, vec!["Documents/old [1].txt".to_string()]);
+
+        let keys = key_mapper("{profile}/{path}");
+        let info = object_info(&[("mtime", "1700000000")]);
+        let path = stored_path(&keys, &unlisted[0], &info).unwrap();
+        manifest.insert(&unlisted[0], ManifestEntry::from_object_info(path, &info));
+
+        let entries = manifest.find("");
+        assert_eq!(entries.len(), 2);
+        let (object_name, entry) = entries.iter().find(|(_, e)| e.path == "Documents/old [1].txt").unwrap();
+        assert_eq!(object_name.as_str(), "Documents/old [1].txt");
+        assert_eq!(entry.checksum.as_deref(), Some("CRC32C:AAAAAA=="));
+        assert_eq!(new_key(&keys, entry, &info), "alice/Documents/old %5B1%5D.txt");
+    }
+
+    #[test]
+    fn stored_path_from_metadata() {
+        let keys = key_mapper("{path}");
+        let info = object_info(&[("path", "Documents/R%C3%A9sum%C3%A9.pdf")]);
+        assert_eq!(stored_path(&keys, "Documents/Re%CC%81sume%CC%81.pdf", &info).unwrap(), "Documents/Résumé.pdf");
+        assert!(stored_path(&keys, "x", &object_info(&[("cse-path", "c2VhbGVk")])).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::future::Future;
+use std::pin::Pin;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{sleep_until, Instant};
+use crate::errors::NotifyError;
+use crate::initialization::{Config, NotifierParameters};
+use crate::mail_manager::{Mail, MailNotifier, Outbox};
+use crate::mail_spool::Spool;
+use crate::metrics::observe_http;
+
+/// Severity of a notification, used for routing
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+
+    /// Returns the severity of a log level
+    ///
+    /// # Arguments
+    ///
+    /// * 'level' - the log level
+    pub fn from_level(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Severity::Error,
+            log::Level::Warn  => Severity::Warning,
+            _                 => Severity::Info,
+        }
+    }
+
+    /// Returns the severity as shown in notifications
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info    => "info",
+            Severity::Warning => "warning",
+            Severity::Error   => "error",
+        }
+    }
+}
+
+/// A channel notifications are sent to, e.g. mail or a chat webhook
+///
+pub trait Notifier: Send + Sync {
+
+    /// Returns the name of the channel
+    ///
+    fn name(&self) -> &str;
+
+    /// Sends a notification
+    ///
+    /// # Arguments
+    ///
+    /// * 'mail' - the notification to send
+    fn send<'a>(&'a self, mail: &'a Mail) -> Pin<Box<dyn Future<Output = Result<(), NotifyError>> + Send + 'a>>;
+}
+
+/// Kinds of HTTP notification channels
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    Webhook,
+    Slack,
+    Ntfy,
+    Gotify,
+}
+
+impl NotifierKind {
+
+    /// Returns the kind as used in metrics
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifierKind::Webhook => "webhook",
+            NotifierKind::Slack   => "slack",
+            NotifierKind::Ntfy    => "ntfy",
+            NotifierKind::Gotify  => "gotify",
+        }
+    }
+}
+
+/// Notification channel posting to an HTTP endpoint:
+/// * webhook - a JSON object with subject, text, severity and event
+/// * slack - a Slack or Mattermost compatible incoming webhook message
+/// * ntfy - a message to an ntfy topic URL, with an optional access token
+/// * gotify - a message to the message URL of a Gotify server, with an application token
+///
+pub struct HttpNotifier {
+    name: String,
+    kind: NotifierKind,
+    url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl HttpNotifier {
+
+    /// Returns a notifier given by configuration
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - notifier configuration parameters
+    pub fn new(config: &NotifierParameters) -> Self {
+        HttpNotifier {
+            name: config.name.clone(),
+            kind: config.kind,
+            url: config.url.clone(),
+            token: config.token.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Returns the request posting a notification
+    ///
+    /// # Arguments
+    ///
+    /// * 'mail' - the notification to send
+    fn request(&self, mail: &Mail) -> reqwest::RequestBuilder {
+        let request = self.client.post(&self.url);
+        match self.kind {
+            NotifierKind::Webhook => request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({
+                    "subject": mail.subject,
+                    "text": mail.text,
+                    "severity": mail.severity,
+                    "event": mail.kind,
+                }).to_string()),
+            NotifierKind::Slack => request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({ "text": format!("*{}*\n{}", mail.subject, mail.text) }).to_string()),
+            NotifierKind::Ntfy => {
+                let priority = match mail.severity {
+                    Severity::Info    => "default",
+                    Severity::Warning => "high",
+                    Severity::Error   => "urgent",
+                };
+                let request = request
+                    .header("Title", &mail.subject)
+                    .header("Priority", priority)
+                    .header("Tags", mail.severity.as_str())
+                    .body(mail.text.clone());
+                match self.token.is_empty() {
+                    true => request,
+                    false => request.bearer_auth(&self.token),
+                }
+            },
+            NotifierKind::Gotify => {
+                let priority = match mail.severity {
+                    Severity::Info    => 2,
+                    Severity::Warning => 5,
+                    Severity::Error   => 8,
+                };
+                request
+                    .header("X-Gotify-Key", &self.token)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(json!({ "title": mail.subject, "message": mail.text, "priority": priority }).to_string())
+            },
+        }
+    }
+}
+
+impl Notifier for HttpNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send<'a>(&'a self, mail: &'a Mail) -> Pin<Box<dyn Future<Output = Result<(), NotifyError>> + Send + 'a>> {
+        Box::pin(async move {
+            let response = observe_http("notify", self.kind.as_str(), self.request(mail).send()).await?;
+            if !response.status().is_success() {
+                return Err(NotifyError(format!("{} responded {}", self.name, response.status())));
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// A notification channel with its routing and outbox
+///
+struct Channel {
+    notifier: Box<dyn Notifier>,
+    min_severity: Severity,
+    events: Vec<String>,
+    outbox: Outbox,
+}
+
+impl Channel {
+
+    /// Returns whether a notification is routed to the channel, which it is if it is at least
+    /// of the minimum severity and, if event types are given, of one of them
+    ///
+    /// # Arguments
+    ///
+    /// * 'mail' - the notification
+    fn routes(&self, mail: &Mail) -> bool {
+        mail.severity >= self.min_severity && (self.events.is_empty() || self.events.contains(&mail.kind))
+    }
+}
+
+/// Sends notifications received over the mpsc channel to every channel they are routed to
+/// Each channel batches log events and limits the number of notifications sent per hour on its
+/// own, and notifications are spooled until delivered and retried with backoff if sending fails
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'rx' - mpsc receiver
+pub async fn notifier(config: &Config, mut rx: Receiver<Mail>) {
+    let mail = match MailNotifier::new(&config.mail) {
+        Ok(mail) => mail,
+        Err(e) => { error!("{}", e); panic!("invalid mail config!") }
+    };
+
+    let mut channels = vec![Channel {
+        notifier: Box::new(mail),
+        min_severity: config.mail.min_severity.unwrap_or_default(),
+        events: config.mail.events.clone(),
+        outbox: Outbox::new(config.mail.batch_window_secs, config.mail.max_mails_per_hour),
+    }];
+    for n in &config.notifiers {
+        channels.push(Channel {
+            notifier: Box::new(HttpNotifier::new(n)),
+            min_severity: n.min_severity.unwrap_or_default(),
+            events: n.events.clone(),
+            outbox: Outbox::new(n.batch_window_secs, n.max_per_hour),
+        });
+    }
+
+    let mut spool = Spool::load(&config.mail).await;
+    loop {
+        for channel in channels.iter_mut() {
+            while let Some(mail) = channel.outbox.next(Instant::now()) {
+                spool.add(channel.notifier.name(), mail).await;
+            }
+        }
+
+        for id in spool.due() {
+            let sent = match spool.get(&id) {
+                Some((name, mail)) => match channels.iter().find(|c| c.notifier.name() == name) {
+                    Some(channel) => channel.notifier.send(mail).await,
+                    None => {
+                        warn!("dropping notification for unknown channel {}", name);
+                        Ok(())
+                    },
+                },
+                None => continue,
+            };
+            match sent {
+                Ok(_) => spool.delivered(&id).await,
+                Err(e) => spool.failed(&id, &e.to_string()).await,
+            }
+        }
+
+        let deadline = channels.iter()
+            .map(|c| c.outbox.deadline())
+            .chain([spool.deadline()])
+            .flatten()
+            .min();
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(mail) => {
+                    for channel in channels.iter_mut().filter(|c| c.routes(&mail)) {
+                        channel.outbox.push(mail.clone());
+                    }
+                },
+                None => {
+                    error!("communication channel to notifier terminated");
+                    break;
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {},
+        }
+    }
+}
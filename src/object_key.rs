@@ -0,0 +1,238 @@
+use chrono::{DateTime, Datelike, Utc};
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use crate::aws_manager::AWS;
+use crate::crypto::{MasterKey, NameKey};
+use crate::errors::ObjectKeyError;
+use crate::initialization::AWS as AWSConfig;
+
+/// The default key template, i.e. the OneDrive path as is
+pub const DEFAULT_TEMPLATE: &str = "{path}";
+
+/// Maximum length in bytes of an S3 object key
+const MAX_KEY_LENGTH: usize = 1024;
+
+/// Characters that are escaped in object keys, besides control characters, since they are
+/// troublesome in URLs, shells and some S3 clients. The '~' is also used to mark hashed keys
+const ESCAPED_CHARACTERS: &str = "\\{}^%`[]\"<>~#|";
+
+/// Separator between the readable part and the hash of a key too long to be stored as is
+const HASH_SEPARATOR: char = '~';
+
+/// Maps OneDrive paths to S3 object keys
+/// The object key is given by a template, where the following placeholders are replaced:
+/// * {path} - the OneDrive path (mandatory)
+/// * {profile} - the configured profile name
+/// * {yyyy}, {mm}, {dd} - year, month and day the file was created on OneDrive (UTC)
+///
+/// The path is Unicode normalized (NFC) and troublesome characters are percent-escaped before
+/// the template is applied. Keys that would exceed the S3 key length limit are truncated and
+/// given a hash of the full key, in which case the real path is only found in the manifest or
+/// object metadata.
+///
+/// Obfuscation, when enabled, is applied to the key given by the template. Any configured
+/// key prefix is added by the AWS manager and is not part of the keys returned here
+///
+pub struct KeyMapper {
+    template: String,
+    profile: String,
+    max_key_length: usize,
+    name_key: Option<NameKey>,
+}
+
+impl KeyMapper {
+
+    /// Creates a new key mapper
+    ///
+    /// # Arguments
+    ///
+    /// * 'master_key' - master key if client side encryption is enabled
+    /// * 'config' - AWS configuration parameters
+    pub fn new(master_key: &Option<MasterKey>, config: &AWSConfig) -> Result<Self, ObjectKeyError> {
+        let name_key = match (master_key, config.obfuscate_keys) {
+            (Some(master_key), true) => Some(master_key.name_key()),
+            (None, true) => {
+                return Err(ObjectKeyError("obfuscated keys requires client side encryption".to_string()));
+            },
+            (_, false) => None,
+        };
+
+        let template = config.key_template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        if !template.contains("{path}") {
+            return Err(ObjectKeyError(format!("key template must contain {{path}}: {}", template)));
+        }
+
+        // The key prefix is added by the AWS manager but counts towards the key length limit
+        let prefix = AWS::normalize_prefix(config.key_prefix.as_deref().unwrap_or_default());
+        let max_key_length = MAX_KEY_LENGTH.saturating_sub(prefix.len());
+        if max_key_length < MAX_KEY_LENGTH / 2 {
+            return Err(ObjectKeyError(format!("key prefix too long: {}", prefix)));
+        }
+
+        Ok(KeyMapper {
+            template: template.to_string(),
+            profile: config.profile.clone().unwrap_or_default(),
+            max_key_length,
+            name_key,
+        })
+    }
+
+    /// Returns true if object keys are the OneDrive paths as is
+    ///
+    pub fn is_plain(&self) -> bool {
+        self.name_key.is_none() && self.template == DEFAULT_TEMPLATE
+    }
+
+    /// Returns true if object keys are obfuscated, in which case no names or content hashes
+    /// may be stored in plain object metadata
+    ///
+    pub fn is_obfuscated(&self) -> bool {
+        self.name_key.is_some()
+    }
+
+    /// Returns the object key to use for a OneDrive file
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path as given by OneDrive::item_info
+    /// * 'created' - creation time of the file as a timestamp
+    pub fn object_key(&self, path: &str, created: i64) -> String {
+        let key = self.apply_template(&Self::escape(path), created);
+
+        match &self.name_key {
+            Some(name_key) => name_key.object_key(&key),
+            None if key.len() > self.max_key_length => self.hashed_key(&key),
+            None => key,
+        }
+    }
+
+    /// Returns the key an object for a OneDrive file was stored under before paths were
+    /// normalized and escaped, or None if that is the same as the current key
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path as given by OneDrive::item_info
+    /// * 'created' - creation time of the file as a timestamp
+    pub fn legacy_key(&self, path: &str, created: i64) -> Option<String> {
+        let key = self.apply_template(path, created);
+        if key == self.apply_template(&Self::escape(path), created) {
+            return None;
+        }
+
+        match &self.name_key {
+            Some(name_key) => Some(name_key.object_key(&key)),
+            None => Some(key),
+        }
+    }
+
+    /// Returns the key given by the key template, before any hashing or obfuscation
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - the path as used in the key
+    /// * 'created' - creation time of the file as a timestamp
+    fn apply_template(&self, path: &str, created: i64) -> String {
+        let created = DateTime::<Utc>::from_timestamp(created, 0).unwrap_or_default();
+
+        self.template
+            .replace("{profile}", &self.profile)
+            .replace("{yyyy}", &format!("{:04}", created.year()))
+            .replace("{mm}", &format!("{:02}", created.month()))
+            .replace("{dd}", &format!("{:02}", created.day()))
+            .replace("{path}", path)
+            .trim_start_matches('/')
+            .to_string()
+    }
+
+    /// Returns a path Unicode normalized (NFC) and with troublesome characters percent-escaped
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path or path prefix
+    pub fn escape(path: &str) -> String {
+        let mut escaped = String::new();
+        for c in path.nfc() {
+            if c.is_control() || ESCAPED_CHARACTERS.contains(c) {
+                let mut buf = [0u8; 4];
+                c.encode_utf8(&mut buf).bytes().for_each(|b| escaped.push_str(&format!("%{:02X}", b)));
+            } else {
+                escaped.push(c);
+            }
+        }
+
+        escaped
+    }
+
+    /// Returns the path an unobfuscated object key was created from, given the default key
+    /// template, or None if the key is hashed and the path thus can't be recovered from it
+    /// Note that the path is returned Unicode normalized (NFC), which may differ from the
+    /// OneDrive path, so paths from the manifest or object metadata should be preferred
+    ///
+    /// # Arguments
+    ///
+    /// * 'object_name' - the object key
+    pub fn unescape(object_name: &str) -> Option<String> {
+        if object_name.contains(HASH_SEPARATOR) {
+            return None;
+        }
+
+        Some(percent_decode_str(object_name).decode_utf8_lossy().to_string())
+    }
+
+    /// Returns a key within the key length limit, made of as much of the key as fits and a
+    /// hex encoded SHA-256 hash of the whole key
+    ///
+    /// # Arguments
+    ///
+    /// * 'key' - the too long key
+    fn hashed_key(&self, key: &str) -> String {
+        let hash: String = Sha256::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+
+        // Cut on a char boundary and not within a percent-escape
+        let mut end = self.max_key_length - 1 - hash.len();
+        while !key.is_char_boundary(end) || key.as_bytes()[end - 2..end].contains(&b'%') {
+            end -= 1;
+        }
+
+        format!("{}{}{}", &key[..end], HASH_SEPARATOR, hash)
+    }
+
+    /// Returns the object key to use for an object not representing a OneDrive item
+    /// such as a deduplicated blob, i.e. the name without applying the key template
+    ///
+    /// # Arguments
+    ///
+    /// * 'name' - the object name
+    pub fn internal_key(&self, name: &str) -> String {
+        match &self.name_key {
+            Some(name_key) => name_key.object_key(name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Returns the real path sealed for storing in object metadata, or None if object
+    /// keys are not obfuscated
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path as given by OneDrive::item_info
+    pub fn sealed_path(&self, path: &str) -> Result<Option<String>, ObjectKeyError> {
+        match &self.name_key {
+            Some(name_key) => Ok(Some(name_key.seal(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the real path from a sealed path as stored in object metadata
+    ///
+    /// # Arguments
+    ///
+    /// * 'sealed' - the sealed path
+    pub fn open_path(&self, sealed: &str) -> Result<String, ObjectKeyError> {
+        match &self.name_key {
+            Some(name_key) => Ok(name_key.open(sealed)?),
+            None => Err(ObjectKeyError("object keys are not obfuscated".to_string())),
+        }
+    }
+}
@@ -1,16 +1,25 @@
 use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use crate::errors::OneDriveError;
-use crate::onedrive_model::{Root, Value};
+use crate::metrics::{observe_http, METRICS};
+use crate::onedrive_model::{Hashes, Root, Value};
 
 #[derive(Debug)]
 pub struct ItemInfo {
     pub filename: String,
+    pub name: String,
     pub item_id: String,
+    pub parent_id: Option<String>,
     pub size: u64,
     pub mtime: i64,
+    pub created: Option<i64>,
+    pub fs_created: Option<i64>,
+    pub fs_modified: Option<i64>,
     pub content_type: Option<String>,
+    pub content_hash: Option<String>,
     pub file: bool,
 }
 
@@ -68,11 +77,11 @@ impl OneDrive {
         let url: &str = &format!("https://graph.microsoft.com/v1.0/me/drive/items/{}/content", item_id);
 
         // Get download url which comes as the Location header value from a redirect 
-        let res = self.client
+        let request = self.client
             .get(url)
             .header("Authorization", &auth)
-            .send()
-            .await?;
+            .send();
+        let res = observe_http("onedrive", "get_download_url", request).await?;
 
         if !res.status().is_redirection() {
             return Err(OneDriveError(format!("get download url status: {}", res.status())));
@@ -85,6 +94,37 @@ impl OneDrive {
         }
     }
 
+    /// Returns the current state of an item, or None if it no longer exists
+    ///
+    /// # Arguments
+    ///
+    /// * 'item_id' - the item id of the file
+    pub async fn get_item(&self, item_id: &str) -> Result<Option<ItemInfo>, OneDriveError> {
+        let auth = format!("Bearer {}", self.access_token);
+        let url: &str = &format!("https://graph.microsoft.com/v1.0/me/drive/items/{}", item_id);
+
+        let request = self.client
+            .get(url)
+            .header("Authorization", &auth)
+            .send();
+        let res = observe_http("onedrive", "get_item", request).await?;
+
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(OneDriveError(format!("get item status: {}", res.status())));
+        }
+
+        let json = res.text().await?;
+        let value: Value = serde_json::from_str(&json)?;
+        if value.deleted.is_some() {
+            return Ok(None);
+        }
+
+        Ok(Some(OneDrive::item_info(value)))
+    }
+
     /// Returns a range from a file
     ///
     /// # Arguments
@@ -93,17 +133,20 @@ impl OneDrive {
     /// * 'from' - first byte to read
     /// * 'to' - last byte to read
     pub async fn get_file_range(&self, url: &str, from: u64, to: u64) -> Result<Vec<u8>, OneDriveError> {
-        let res = self.client
+        let request = self.client
             .get(url)
             .header("Range", format!("bytes={}-{}", from, to))
-            .send()
-            .await?;
+            .send();
+        let res = observe_http("onedrive", "get_file_range", request).await?;
 
         if !res.status().is_success() {
             return Err(OneDriveError(format!("get file status: {}", res.status())));
         }
 
-        Ok(res.bytes().await?.to_vec())
+        let bytes = res.bytes().await?.to_vec();
+        METRICS.bytes.with_label_values(&["onedrive", "download"]).inc_by(bytes.len() as u64);
+
+        Ok(bytes)
     }
 
     /// Returns a file
@@ -112,16 +155,19 @@ impl OneDrive {
     ///
     /// * 'url' - the download url as gotten from get_download_url
     pub async fn get_file(&self, url: &str) -> Result<Vec<u8>, OneDriveError> {
-        let res = self.client
+        let request = self.client
             .get(url)
-            .send()
-            .await?;
+            .send();
+        let res = observe_http("onedrive", "get_file", request).await?;
 
         if !res.status().is_success() {
             return Err(OneDriveError(format!("get file status: {}", res.status())));
         }
 
-        Ok(res.bytes().await?.to_vec())
+        let bytes = res.bytes().await?.to_vec();
+        METRICS.bytes.with_label_values(&["onedrive", "download"]).inc_by(bytes.len() as u64);
+
+        Ok(bytes)
     }
     
     /// Returns all deltas since last call for deltas
@@ -137,11 +183,11 @@ impl OneDrive {
 
         let mut deltas: Vec<ItemInfo> = Vec::new();
         loop {
-            let res = self.client
+            let request = self.client
                 .get(&url)
                 .header("Authorization", &auth)
-                .send()
-                .await?;
+                .send();
+            let res = observe_http("onedrive", "get_delta", request).await?;
 
             if !res.status().is_success() {
                 return Err(OneDriveError(format!("Get delta status: {}", res.status())));
@@ -211,27 +257,59 @@ impl OneDrive {
     /// 
     /// * 'value' - the Value struct to convert
     fn item_info(value: Value) -> ItemInfo {
+        let name = value.name.unwrap();
         let mut filename = value.parent_reference.path
             .unwrap()
             .split_once(':')
             .unwrap().1
-            .to_string() + "/" + &value.name.unwrap();
+            .to_string() + "/" + &name;
         
         filename = filename.trim_start_matches('/').to_string();
 
-        let (file, content_type) = if let Some(file) = value.file {
-            (true, file.mime_type)
+        let (file, content_type, content_hash) = if let Some(file) = value.file {
+            (true, file.mime_type, file.hashes.and_then(OneDrive::content_hash))
         } else {
-            (false, None)
+            (false, None, None)
         };
         
+        let (fs_created, fs_modified) = match value.file_system_info {
+            Some(info) => (
+                info.created_date_time.map(|t| t.timestamp()),
+                info.last_modified_date_time.map(|t| t.timestamp()),
+            ),
+            None => (None, None),
+        };
+
         ItemInfo {
             filename,
+            name,
             item_id: value.id,
+            parent_id: value.parent_reference.id,
             size: value.size,
             mtime: value.last_modified_date_time.unwrap().timestamp(),
+            created: value.created_date_time.map(|t| t.timestamp()),
+            fs_created,
+            fs_modified,
             content_type,
+            content_hash,
             file,
         }
     }
+
+    /// Returns the strongest available content hash as <algorithm>-<lowercase hex>
+    ///
+    /// # Arguments
+    ///
+    /// * 'hashes' - the hashes given by OneDrive for a file
+    fn content_hash(hashes: Hashes) -> Option<String> {
+        if let Some(sha256) = hashes.sha256_hash {
+            Some(format!("sha256-{}", sha256.to_lowercase()))
+        } else if let Some(sha1) = hashes.sha1_hash {
+            Some(format!("sha1-{}", sha1.to_lowercase()))
+        } else {
+            hashes.quick_xor_hash
+                .and_then(|h| BASE64.decode(h).ok())
+                .map(|h| format!("qxh-{}", h.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+        }
+    }
 }
@@ -4,20 +4,42 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Deleted {}
 
+#[derive(Deserialize)]
+pub struct Hashes {
+    #[serde(rename = "quickXorHash")]
+    pub quick_xor_hash: Option<String>,
+    #[serde(rename = "sha1Hash")]
+    pub sha1_hash: Option<String>,
+    #[serde(rename = "sha256Hash")]
+    pub sha256_hash: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct File {
     #[serde(rename = "mimeType")]
     pub mime_type: Option<String>,
+    pub hashes: Option<Hashes>,
 }
 
 #[derive(Deserialize)]
 pub struct ParentReference {
+    pub id: Option<String>,
     pub path: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct FileSystemInfo {
+    #[serde(rename = "createdDateTime")]
+    pub created_date_time: Option<DateTime<Utc>>,
+    #[serde(rename = "lastModifiedDateTime")]
+    pub last_modified_date_time: Option<DateTime<Utc>>,
+}
+
 #[derive(Deserialize)]
 pub struct Value {
     pub id: String,
+    #[serde(rename = "createdDateTime")]
+    pub created_date_time: Option<DateTime<Utc>>,
     #[serde(rename = "lastModifiedDateTime")]
     pub last_modified_date_time: Option<DateTime<Utc>>,
     pub name: Option<String>,
@@ -26,6 +48,8 @@ pub struct Value {
     pub parent_reference: ParentReference,
     pub deleted: Option<Deleted>,
     pub file: Option<File>,
+    #[serde(rename = "fileSystemInfo")]
+    pub file_system_info: Option<FileSystemInfo>,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,139 @@
+use chrono::Local;
+use crate::history::{Change, RunRecord};
+use crate::mail_manager::{Attachment, Mail};
+use crate::notifier::Severity;
+use crate::status::escape;
+
+/// Number of largest files listed in a run report
+const LARGEST_FILES: usize = 10;
+
+/// Returns the end-of-run report mail for a run, with a plain text and an HTML version and
+/// the list of changed files attached as CSV
+///
+/// # Arguments
+///
+/// * 'run' - the finished run
+pub fn run_report(run: &RunRecord) -> Mail {
+    let subject = match &run.error {
+        None => format!("CloudSync run succeeded: {} updates, {} adds, {} renames", run.updated, run.added, run.renamed),
+        Some(_) => "CloudSync run FAILED".to_string(),
+    };
+
+    let duration = run.duration();
+    let throughput = if duration > 0 { run.bytes / duration as u64 } else { run.bytes };
+    let totals: Vec<(&str, String)> = vec![
+        ("Started", run.started.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()),
+        ("Trigger", run.trigger.as_str().to_string()),
+        ("Result", run.error.as_ref().map(|e| format!("failed: {}", e)).unwrap_or("ok".to_string())),
+        ("Duration", format!("{} s", duration)),
+        ("Updates", run.updated.to_string()),
+        ("Adds", run.added.to_string()),
+        ("Renames", run.renamed.to_string()),
+        ("Skipped (unchanged)", run.skipped.to_string()),
+        ("Excluded (not files)", run.excluded.to_string()),
+        ("Bytes stored", human_bytes(run.bytes)),
+        ("Throughput", format!("{}/s", human_bytes(throughput))),
+        ("Verified / retried / failed", format!("{} / {} / {}", run.verified, run.verify_retried, run.verify_failed)),
+    ];
+
+    let mut largest: Vec<&Change> = run.changes.iter().filter(|c| c.action != "renamed").collect();
+    largest.sort_by_key(|c| std::cmp::Reverse(c.size));
+    largest.truncate(LARGEST_FILES);
+
+    let mut text = String::new();
+    let mut html = String::from("<html><body style=\"font-family:sans-serif\"><h2>CloudSync run report</h2>\n<table>\n");
+    for (name, value) in &totals {
+        text.push_str(&format!("{}: {}\n", name, value));
+        html.push_str(&format!("<tr><th align=\"left\">{}</th><td>{}</td></tr>\n", name, escape(value)));
+    }
+    html.push_str("</table>\n");
+
+    if !largest.is_empty() {
+        text.push_str("\nLargest files:\n");
+        html.push_str("<h3>Largest files</h3>\n<table>\n");
+        for c in &largest {
+            text.push_str(&format!("  {:>10}  {}\n", human_bytes(c.size), c.path));
+            html.push_str(&format!("<tr><td align=\"right\">{}</td><td>{}</td></tr>\n", human_bytes(c.size), escape(&c.path)));
+        }
+        html.push_str("</table>\n");
+    }
+
+    if !run.failures.is_empty() {
+        text.push_str("\nFailures:\n");
+        html.push_str("<h3>Failures</h3>\n<ul>\n");
+        for f in &run.failures {
+            text.push_str(&format!("  {}\n", f));
+            html.push_str(&format!("<li>{}</li>\n", escape(f)));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    if !run.changes.is_empty() {
+        text.push_str(&format!("\nThe {} changed files are listed in the attached changes.csv\n", run.changes.len()));
+        html.push_str(&format!("<p>The {} changed files are listed in the attached changes.csv</p>\n", run.changes.len()));
+    }
+    html.push_str("</body></html>\n");
+
+    let attachments = if run.changes.is_empty() {
+        Vec::new()
+    } else {
+        vec![Attachment {
+            filename: format!("changes-{}.csv", run.id),
+            content_type: "text/csv".to_string(),
+            content: changes_csv(&run.changes).into_bytes(),
+        }]
+    };
+
+    let severity = if run.success { Severity::Info } else { Severity::Error };
+
+    Mail { key: None, severity, kind: "report".to_string(), subject, text, html: Some(html), attachments }
+}
+
+/// Returns changes as CSV with a header row
+///
+/// # Arguments
+///
+/// * 'changes' - the changes
+fn changes_csv(changes: &[Change]) -> String {
+    let mut csv = String::from("action,key,path,size\r\n");
+    for c in changes {
+        csv.push_str(&format!("{},{},{},{}\r\n", c.action, csv_field(&c.key), csv_field(&c.path), c.size));
+    }
+
+    csv
+}
+
+/// Returns a CSV field, quoted if needed
+///
+/// # Arguments
+///
+/// * 'value' - the field value
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Returns a byte count in a human readable form, e.g. 1.5 GiB
+///
+/// # Arguments
+///
+/// * 'bytes' - the byte count
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,264 @@
+use std::fs::FileTimes;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use tokio::io::AsyncWriteExt;
+use crate::aws_manager::AWS;
+use crate::crypto::MasterKey;
+use crate::errors::RestoreError;
+use crate::initialization::Config;
+use crate::manifest::{Manifest, ManifestEntry, INTERNAL_PREFIX, MANIFEST_KEY};
+use crate::object_key::KeyMapper;
+use crate::transform::RestoreTransform;
+
+struct Restorer {
+    aws: AWS,
+    master_key: Option<MasterKey>,
+    keys: KeyMapper,
+}
+
+impl Restorer {
+
+    /// Creates a new restorer
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - configuration struct
+    async fn new(config: &Config) -> Result<Self, RestoreError> {
+        let aws = AWS::new(&config.aws).await?;
+        let master_key = if config.aws.client_side_encryption {
+            Some(MasterKey::new(&config.aws.cse_master_key)?)
+        } else {
+            None
+        };
+        let keys = KeyMapper::new(&master_key, &config.aws)?;
+
+        Ok(Restorer { aws, master_key, keys })
+    }
+}
+
+/// Restores all objects with paths starting with the given prefix to a local directory
+/// Objects are decrypted (if client side encrypted) and given their original last
+/// modification time
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'prefix' - the OneDrive path (prefix) to restore
+/// * 'dir' - local directory to restore to
+pub async fn restore(config: &Config, prefix: &str, dir: &str) -> Result<(), RestoreError> {
+    let restorer = Restorer::new(config).await?;
+
+    let objects = find_objects(&restorer, config, prefix).await?;
+    if objects.is_empty() {
+        return Err(RestoreError(format!("no objects found for: {}", prefix)));
+    }
+
+    for (object_name, path) in objects {
+        let local_path = match local_path(dir, &path) {
+            Some(local_path) => local_path,
+            None => {
+                warn!("skipping object with a path outside the restore directory: {} ({})", object_name, path);
+                continue;
+            },
+        };
+
+        restore_object(&restorer, &object_name, &local_path).await?;
+        info!("restored: {}", path);
+        println!("{}", local_path.display());
+    }
+
+    Ok(())
+}
+
+/// Returns the local path to restore a file to, inside the restore directory
+/// Root and drive prefixes are stripped so that absolute paths are restored relative to the
+/// directory, while paths climbing out of it with ".." are rejected
+///
+/// # Arguments
+///
+/// * 'dir' - local directory to restore to
+/// * 'path' - the OneDrive path of the file
+fn local_path(dir: &str, path: &str) -> Option<PathBuf> {
+    let mut local_path = PathBuf::from(dir);
+    let mut empty = true;
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => {
+                local_path.push(name);
+                empty = false;
+            },
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+            Component::ParentDir => return None,
+        }
+    }
+
+    (!empty).then_some(local_path)
+}
+
+/// Lists the real paths of all objects with paths starting with the given prefix,
+/// together with the object key if object keys are not the plain OneDrive paths
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'prefix' - the OneDrive path (prefix) to list
+pub async fn list(config: &Config, prefix: &str) -> Result<(), RestoreError> {
+    let restorer = Restorer::new(config).await?;
+
+    for (object_name, path) in find_objects(&restorer, config, prefix).await? {
+        if !restorer.keys.is_plain() {
+            println!("{}  {}", object_name, path);
+        } else {
+            println!("{}", path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns object keys and real paths for all objects with paths starting with the given prefix
+///
+/// # Arguments
+///
+/// * 'restorer' - the restorer
+/// * 'config' - configuration struct
+/// * 'prefix' - the OneDrive path (prefix)
+async fn find_objects(restorer: &Restorer, config: &Config, prefix: &str) -> Result<Vec<(String, String)>, RestoreError> {
+    let prefix = prefix.trim_start_matches('/');
+
+    if !restorer.keys.is_plain() {
+        let manifest = load_manifest(restorer, config).await?;
+        Ok(manifest.find(prefix)
+            .into_iter()
+            .map(|(k, e)| (k.clone(), e.path.clone()))
+            .collect())
+    } else {
+        // Object keys are the escaped paths, but the exact OneDrive paths are found in the
+        // manifest, or for keys that don't reproduce them, in the object metadata
+        let manifest = Manifest::from_file(&config.manifest_path()).await?;
+        let mut objects: Vec<(String, String)> = Vec::new();
+        for object_name in restorer.aws.list_objects(&KeyMapper::escape(prefix)).await? {
+            if object_name.starts_with(INTERNAL_PREFIX) {
+                continue;
+            }
+            match object_path(restorer, &manifest, &object_name).await? {
+                Some(path) => objects.push((object_name, path)),
+                None => warn!("no path found for object: {}", object_name),
+            }
+        }
+        objects.sort_by(|a, b| a.1.cmp(&b.1));
+
+        Ok(objects)
+    }
+}
+
+/// Returns the OneDrive path for an object stored with the default key template
+///
+/// # Arguments
+///
+/// * 'restorer' - the restorer
+/// * 'manifest' - the local manifest
+/// * 'object_name' - name and path to the S3 object
+async fn object_path(restorer: &Restorer, manifest: &Manifest, object_name: &str) -> Result<Option<String>, RestoreError> {
+    if let Some(entry) = manifest.get(object_name) {
+        return Ok(Some(entry.path.clone()));
+    }
+
+    let path = restorer.aws.get_object_info(object_name).await?
+        .and_then(|info| info.metadata.get("path").cloned())
+        .map(|path| percent_decode_str(&path).decode_utf8_lossy().to_string());
+
+    Ok(path.or(KeyMapper::unescape(object_name)))
+}
+
+/// Loads the manifest from the local file if present, otherwise from the copy in the bucket
+/// If neither exists, the manifest is rebuilt from the (sealed) paths in object metadata
+///
+/// # Arguments
+///
+/// * 'restorer' - the restorer
+/// * 'config' - configuration struct
+async fn load_manifest(restorer: &Restorer, config: &Config) -> Result<Manifest, RestoreError> {
+    if Path::new(&config.manifest_path()).exists() {
+        return Ok(Manifest::from_file(&config.manifest_path()).await?);
+    }
+
+    if restorer.aws.get_object_info(MANIFEST_KEY).await?.is_some() {
+        info!("loading manifest from bucket");
+        let json = read_object(restorer, MANIFEST_KEY).await?;
+        return Ok(Manifest::from_bytes(&json)?);
+    }
+
+    warn!("no manifest found, rebuilding it from object metadata");
+    let mut manifest = Manifest::default();
+    for object_name in restorer.aws.list_objects("").await? {
+        if let Some(info) = restorer.aws.get_object_info(&object_name).await? {
+            let path = match (info.metadata.get("cse-path"), info.metadata.get("path")) {
+                (Some(sealed_path), _) => Some(restorer.keys.open_path(sealed_path)?),
+                (None, Some(path)) => Some(percent_decode_str(path).decode_utf8_lossy().to_string()),
+                (None, None) => None,
+            };
+            if let Some(path) = path {
+                manifest.insert(&object_name, ManifestEntry::from_object_info(path, &info));
+            }
+        }
+    }
+
+    Ok(manifest)
+}
+
+/// Reads an entire object into memory and reverses the upload transform
+///
+/// # Arguments
+///
+/// * 'restorer' - the restorer
+/// * 'object_name' - name and path to the S3 object
+async fn read_object(restorer: &Restorer, object_name: &str) -> Result<Vec<u8>, RestoreError> {
+    let mut object = restorer.aws.get_object(object_name).await?;
+    let mut transform = RestoreTransform::new(&restorer.master_key, &object.metadata)?;
+
+    let mut content: Vec<u8> = Vec::new();
+    while let Some(bytes) = object.next().await? {
+        content.extend(transform.update(bytes)?);
+    }
+    content.extend(transform.finish()?);
+
+    Ok(content)
+}
+
+/// Restores one object to a local file
+/// If the object is a pointer to a deduplicated blob, the content is read from the blob
+///
+/// # Arguments
+///
+/// * 'restorer' - the restorer
+/// * 'object_name' - name and path to the S3 object
+/// * 'path' - local file to write
+async fn restore_object(restorer: &Restorer, object_name: &str, path: &Path) -> Result<(), RestoreError> {
+    let mut object = restorer.aws.get_object(object_name).await?;
+    let mtime = object.metadata.get("mtime").and_then(|m| m.parse::<u64>().ok());
+    if let Some(blob_name) = object.metadata.get("blob").cloned() {
+        object = restorer.aws.get_object(&blob_name).await?;
+    }
+    let mut transform = RestoreTransform::new(&restorer.master_key, &object.metadata)?;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = tokio::fs::File::create(path).await?;
+
+    while let Some(bytes) = object.next().await? {
+        file.write_all(&transform.update(bytes)?).await?;
+    }
+    file.write_all(&transform.finish()?).await?;
+    file.flush().await?;
+
+    if let Some(mtime) = mtime {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime);
+        file.into_std().await.set_times(FileTimes::new().set_modified(modified))?;
+    }
+
+    Ok(())
+}
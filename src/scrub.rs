@@ -0,0 +1,176 @@
+use chrono::Utc;
+use log::{info, warn};
+use rand::seq::IteratorRandom;
+use crate::aws_manager::AWS;
+use crate::content_hash::ContentHasher;
+use crate::crypto::MasterKey;
+use crate::errors::ScrubError;
+use crate::initialization::Config;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::metrics::{result, METRICS};
+use crate::onedrive_manager::OneDrive;
+use crate::token_manager::Tokens;
+use crate::transform::RestoreTransform;
+
+/// Number of objects checked per scrub run if neither a sample size nor a rotation is configured
+const DEFAULT_SAMPLE_SIZE: usize = 100;
+
+/// Result of scrubbing one object
+enum Outcome {
+    Verified,
+    Skipped,
+    Archived,
+    Discrepancy(String),
+}
+
+/// Spot-checks stored objects against OneDrive and mails a report of any discrepancies
+/// Objects are picked from the manifest, either as a random sample or, if a rotation is
+/// configured, as the share of objects due today so that all are checked over the rotation.
+/// Each object is downloaded, its upload transform reversed and its content hashed, and the
+/// hash compared with the current OneDrive hash of the item. Items changed since they were
+/// stored are skipped, as are objects in archive storage classes which can't be read directly
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct
+/// * 'one_drive' - the OneDrive manager
+/// * 'tokens' - the OneDrive tokens, refreshed when needed
+/// * 'aws' - the AWS manager
+/// * 'master_key' - master key if client side encryption is enabled
+/// * 'manifest' - the manifest of stored objects
+pub async fn scrub(config: &Config, one_drive: &mut OneDrive, tokens: &mut Tokens, aws: &AWS, master_key: &Option<MasterKey>, manifest: &Manifest) -> Result<(), ScrubError> {
+    let selected = select(manifest.find(""), config.scrub.sample_size, config.scrub.rotation_days);
+    info!("scrubbing {} objects", selected.len());
+
+    let mut verified = 0;
+    let mut skipped = 0;
+    let mut archived = 0;
+    let mut discrepancies: Vec<String> = Vec::new();
+    for (object_name, entry) in &selected {
+        if tokens.is_expired() {
+            let refreshed = tokens.refresh_tokens(&config.onedrive).await;
+            METRICS.token_refreshes.with_label_values(&[result(refreshed.is_ok())]).inc();
+            refreshed?;
+            one_drive.set_access_token(&tokens.get_access_token());
+        }
+
+        let outcome = match check_object(one_drive, aws, master_key, object_name, entry).await {
+            Ok(outcome) => outcome,
+            Err(e) => Outcome::Discrepancy(format!("check failed: {}", e)),
+        };
+        match outcome {
+            Outcome::Verified => verified += 1,
+            Outcome::Skipped => skipped += 1,
+            Outcome::Archived => archived += 1,
+            Outcome::Discrepancy(reason) => {
+                warn!("scrub discrepancy for {:?}: {}", entry.path, reason);
+                discrepancies.push(format!("{}: {}", entry.path, reason));
+            },
+        }
+    }
+
+    let summary = format!("Done scrubbing objects! Checked: {}, Verified: {}, Skipped: {}, Archived: {}, Discrepancies: {}",
+        selected.len(), verified, skipped, archived, discrepancies.len());
+    if discrepancies.is_empty() {
+        info!(target: "mail", "{}", summary);
+    } else {
+        warn!(target: "mail", "{}\n\n{}", summary, discrepancies.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Returns the manifest entries to scrub
+/// With a rotation each object is due on a fixed day of the rotation, given by a hash of its
+/// key, so objects added later don't shift which objects are due on other days
+///
+/// # Arguments
+///
+/// * 'entries' - all manifest entries
+/// * 'sample_size' - number of objects to pick at random
+/// * 'rotation_days' - number of days to check all objects over, overrides the sample size
+fn select<'a>(entries: Vec<(&'a String, &'a ManifestEntry)>, sample_size: Option<usize>, rotation_days: Option<u32>) -> Vec<(&'a String, &'a ManifestEntry)> {
+    match rotation_days {
+        Some(days) if days > 0 => {
+            let today = (Utc::now().timestamp() / 86400) as u32 % days;
+            entries.into_iter()
+                .filter(|(k, _)| crc32c::crc32c(k.as_bytes()) % days == today)
+                .collect()
+        },
+        _ => {
+            let sample_size = sample_size.unwrap_or(DEFAULT_SAMPLE_SIZE);
+            entries.into_iter().choose_multiple(&mut rand::rng(), sample_size)
+        },
+    }
+}
+
+/// Checks one object against the current state of its OneDrive item
+///
+/// # Arguments
+///
+/// * 'one_drive' - the OneDrive manager
+/// * 'aws' - the AWS manager
+/// * 'master_key' - master key if client side encryption is enabled
+/// * 'object_name' - name and path of the S3 object
+/// * 'entry' - manifest entry of the object
+async fn check_object(one_drive: &OneDrive, aws: &AWS, master_key: &Option<MasterKey>, object_name: &str, entry: &ManifestEntry) -> Result<Outcome, ScrubError> {
+    let item = match one_drive.get_item(&entry.item_id).await? {
+        Some(item) => item,
+        None => return Ok(Outcome::Skipped),
+    };
+    let info = match aws.get_object_info(object_name).await? {
+        Some(info) => info,
+        None => return Ok(Outcome::Discrepancy("object missing".to_string())),
+    };
+
+    // Changed items are uploaded again by the next sync, the stored object can't be compared
+    if info.mtime != Some(item.mtime) || info.size != Some(item.size) {
+        return Ok(Outcome::Skipped);
+    }
+    let (content_hash, hasher) = match item.content_hash.as_deref().and_then(|h| Some((h, ContentHasher::for_hash(h)?))) {
+        Some(hash) => hash,
+        None => return Ok(Outcome::Skipped),
+    };
+
+    // The content of a pointer object is in its deduplicated blob
+    let (content_name, info) = match info.metadata.get("blob") {
+        Some(blob_name) => match aws.get_object_info(blob_name).await? {
+            Some(blob_info) => (blob_name.clone(), blob_info),
+            None => return Ok(Outcome::Discrepancy(format!("blob missing: {}", blob_name))),
+        },
+        None => (object_name.to_string(), info),
+    };
+    if info.is_archived() {
+        return Ok(Outcome::Archived);
+    }
+
+    let hash = match hash_object(aws, master_key, &content_name, hasher).await {
+        Ok(hash) => hash,
+        Err(e) => return Ok(Outcome::Discrepancy(format!("unreadable: {}", e))),
+    };
+    if hash != content_hash {
+        return Ok(Outcome::Discrepancy(format!("content hash {}, expected {}", hash, content_hash)));
+    }
+
+    Ok(Outcome::Verified)
+}
+
+/// Downloads an object, reverses the upload transform and returns the content hash
+///
+/// # Arguments
+///
+/// * 'aws' - the AWS manager
+/// * 'master_key' - master key if client side encryption is enabled
+/// * 'object_name' - name and path of the S3 object
+/// * 'hasher' - hasher of the algorithm to compare with
+async fn hash_object(aws: &AWS, master_key: &Option<MasterKey>, object_name: &str, mut hasher: ContentHasher) -> Result<String, ScrubError> {
+    let mut object = aws.get_object(object_name).await?;
+    let mut transform = RestoreTransform::new(master_key, &object.metadata)?;
+
+    while let Some(bytes) = object.next().await? {
+        hasher.update(&transform.update(bytes)?);
+    }
+    hasher.update(&transform.finish()?);
+
+    Ok(hasher.finish())
+}
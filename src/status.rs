@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use serde::Serialize;
+use crate::initialization::OneDrive;
+use crate::token_manager::Tokens;
+
+/// Number of recent errors kept for the status page
+const MAX_RECENT_ERRORS: usize = 20;
+
+/// What the sync is currently doing
+#[derive(Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    #[default]
+    Starting,
+    Sleeping,
+    Running,
+    Scrubbing,
+    Paused,
+}
+
+impl SyncState {
+
+    /// Returns the state as shown on the dashboard
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncState::Starting  => "starting",
+            SyncState::Sleeping  => "sleeping",
+            SyncState::Running   => "running",
+            SyncState::Scrubbing => "scrubbing",
+            SyncState::Paused    => "paused, waiting for re-authorization",
+        }
+    }
+}
+
+/// State of the OneDrive tokens
+#[derive(Serialize, Clone, Default)]
+pub struct TokenStatus {
+    pub valid: bool,
+    pub granted_at: Option<DateTime<Utc>>,
+    pub refreshed_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub refresh_expires_at: Option<DateTime<Utc>>,
+}
+
+/// Progress of the current sync run
+#[derive(Serialize, Clone)]
+pub struct Progress {
+    pub started: DateTime<Utc>,
+    pub file: usize,
+    pub files: usize,
+    pub bytes: u64,
+    pub current: Option<String>,
+}
+
+/// Summary of a completed sync run
+#[derive(Serialize, Clone)]
+pub struct RunSummary {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub updated: u32,
+    pub added: u32,
+    pub renamed: u32,
+    pub bytes: u64,
+    pub verified: u32,
+    pub verify_retried: u32,
+    pub verify_failed: u32,
+}
+
+/// An error reported by the sync
+#[derive(Serialize, Clone)]
+pub struct ErrorEntry {
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Status of the service as shown by /status and the dashboard
+#[derive(Serialize, Clone, Default)]
+pub struct Status {
+    pub state: SyncState,
+    pub next_run: Option<DateTime<Local>>,
+    pub tokens: TokenStatus,
+    pub progress: Option<Progress>,
+    pub last_run: Option<RunSummary>,
+    pub recent_errors: VecDeque<ErrorEntry>,
+}
+
+/// Status shared between the sync and the web server, updated by the sync as it runs
+///
+#[derive(Clone, Default)]
+pub struct SyncStatus(Arc<Mutex<Status>>);
+
+impl SyncStatus {
+
+    /// Updates the status
+    ///
+    /// # Arguments
+    ///
+    /// * 'f' - function making the update
+    pub fn update(&self, f: impl FnOnce(&mut Status)) {
+        let mut status = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut status);
+    }
+
+    /// Returns a copy of the current status
+    ///
+    pub fn snapshot(&self) -> Status {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Adds an error to the recent errors, dropping the oldest if there are too many
+    ///
+    /// # Arguments
+    ///
+    /// * 'message' - the error message
+    pub fn error(&self, message: String) {
+        self.update(|s| {
+            s.recent_errors.push_front(ErrorEntry { time: Utc::now(), message });
+            s.recent_errors.truncate(MAX_RECENT_ERRORS);
+        });
+    }
+
+    /// Records the state of valid tokens
+    ///
+    /// # Arguments
+    ///
+    /// * 'tokens' - the current tokens
+    /// * 'config' - configuration struct for OneDrive
+    pub fn tokens(&self, tokens: &Tokens, config: &OneDrive) {
+        self.update(|s| s.tokens = TokenStatus {
+            valid: true,
+            granted_at: Some(tokens.granted_at),
+            refreshed_at: Some(tokens.refreshed_at),
+            expires_at: Some(tokens.refreshed_at + TimeDelta::seconds(tokens.expires_in)),
+            refresh_expires_at: Some(tokens.refresh_expires_at(config)),
+        });
+    }
+}
+
+/// Renders the status as a simple HTML page, reloading itself every 30 seconds
+///
+/// # Arguments
+///
+/// * 'status' - the status to render
+pub fn dashboard(status: &Status) -> String {
+    let time = |t: Option<DateTime<Utc>>| t.map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or("-".to_string());
+
+    let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"30\">\
+        <title>CloudSync status</title><style>body{font-family:sans-serif;margin:2em}td,th{padding:2px 12px;text-align:left}</style>\
+        </head><body><h1>CloudSync status</h1>\n");
+
+    html.push_str("<h2>Sync</h2><table>\n");
+    row(&mut html, "State", status.state.as_str());
+    row(&mut html, "Next run", &status.next_run.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or("-".to_string()));
+    if let Some(p) = &status.progress {
+        row(&mut html, "Started", &time(Some(p.started)));
+        row(&mut html, "Progress", &format!("file {} of {}, {} bytes", p.file, p.files, p.bytes));
+        row(&mut html, "Current file", p.current.as_deref().unwrap_or("-"));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Tokens</h2><table>\n");
+    row(&mut html, "Valid", if status.tokens.valid { "yes" } else { "no" });
+    row(&mut html, "Granted", &time(status.tokens.granted_at));
+    row(&mut html, "Refreshed", &time(status.tokens.refreshed_at));
+    row(&mut html, "Access token expires", &time(status.tokens.expires_at));
+    row(&mut html, "Refresh token expires (estimated)", &time(status.tokens.refresh_expires_at));
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Last run</h2><table>\n");
+    match &status.last_run {
+        Some(r) => {
+            row(&mut html, "Started", &time(Some(r.started)));
+            row(&mut html, "Finished", &time(Some(r.finished)));
+            row(&mut html, "Updates / adds / renames", &format!("{} / {} / {}", r.updated, r.added, r.renamed));
+            row(&mut html, "Bytes", &r.bytes.to_string());
+            row(&mut html, "Verified / retried / failed", &format!("{} / {} / {}", r.verified, r.verify_retried, r.verify_failed));
+        },
+        None => row(&mut html, "Finished", "-"),
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Recent errors</h2><table>\n");
+    for e in &status.recent_errors {
+        row(&mut html, &time(Some(e.time)), &e.message);
+    }
+    html.push_str("</table>\n</body></html>\n");
+
+    html
+}
+
+/// Adds a table row with a heading and an HTML escaped value
+///
+/// # Arguments
+///
+/// * 'html' - the page to add to
+/// * 'heading' - row heading
+/// * 'value' - row value
+fn row(html: &mut String, heading: &str, value: &str) {
+    html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", escape(heading), escape(value)));
+}
+
+/// Returns text with HTML special characters escaped
+///
+/// # Arguments
+///
+/// * 'text' - the text to escape
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,88 @@
+use aws_sdk_s3::types::StorageClass;
+use globset::{GlobBuilder, GlobMatcher};
+use crate::errors::AWSError;
+use crate::initialization::StorageClassRule;
+
+/// A compiled storage class rule
+///
+struct Rule {
+    matcher: GlobMatcher,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    storage_class: StorageClass,
+}
+
+/// Maps OneDrive paths and file sizes to an S3 storage class
+/// Rules are evaluated in configured order and the first matching rule wins. If no rule
+/// matches the default storage class is used, and if that is not given either the bucket
+/// default (normally STANDARD) applies
+///
+pub struct StorageClassRules {
+    rules: Vec<Rule>,
+    default: Option<StorageClass>,
+}
+
+impl StorageClassRules {
+
+    /// Compiles storage class rules from configuration
+    ///
+    /// # Arguments
+    ///
+    /// * 'rules' - storage class rules from configuration
+    /// * 'default' - storage class to use when no rule matches
+    pub fn new(rules: &[StorageClassRule], default: &Option<String>) -> Result<Self, AWSError> {
+        let mut compiled: Vec<Rule> = Vec::new();
+        for rule in rules {
+            let matcher = GlobBuilder::new(&rule.pattern)
+                .literal_separator(true)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| AWSError(format!("storage class rule pattern: {}", e)))?
+                .compile_matcher();
+
+            compiled.push(Rule {
+                matcher,
+                min_size: rule.min_size,
+                max_size: rule.max_size,
+                storage_class: Self::parse_storage_class(&rule.storage_class)?,
+            });
+        }
+
+        let default = match default {
+            Some(class) => Some(Self::parse_storage_class(class)?),
+            None => None,
+        };
+
+        Ok(StorageClassRules { rules: compiled, default })
+    }
+
+    /// Returns the storage class to use for a file
+    ///
+    /// # Arguments
+    ///
+    /// * 'path' - OneDrive path of the file (without leading slash)
+    /// * 'size' - size of the file on OneDrive
+    pub fn storage_class(&self, path: &str, size: u64) -> Option<StorageClass> {
+        self.rules.iter()
+            .find(|r| {
+                r.matcher.is_match(path)
+                    && r.min_size.is_none_or(|min| size >= min)
+                    && r.max_size.is_none_or(|max| size <= max)
+            })
+            .map(|r| r.storage_class.clone())
+            .or_else(|| self.default.clone())
+    }
+
+    /// Parses and validates a storage class name such as DEEP_ARCHIVE
+    ///
+    /// # Arguments
+    ///
+    /// * 'class' - name of the storage class
+    fn parse_storage_class(class: &str) -> Result<StorageClass, AWSError> {
+        if StorageClass::values().contains(&class) {
+            Ok(StorageClass::from(class))
+        } else {
+            Err(AWSError(format!("unknown storage class: {}", class)))
+        }
+    }
+}
@@ -1,9 +1,39 @@
 use std::fs;
 use std::path::Path;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
 use crate::initialization::OneDrive;
 use crate::errors::TokenError;
+use crate::metrics::{observe_http, METRICS};
+
+/// Days a refresh token stays valid without being used if no lifetime is configured
+const DEFAULT_REFRESH_TOKEN_LIFETIME_DAYS: u32 = 90;
+
+/// Days after authorization that re-authorizing is due if no maximum grant age is configured,
+/// since a refresh token used every sync run otherwise never comes close to its lifetime
+const DEFAULT_MAX_GRANT_AGE_DAYS: u32 = 90;
+
+/// Days before the refresh token is estimated to expire that warnings start if not configured
+const DEFAULT_TOKEN_WARNING_DAYS: u32 = 14;
+
+/// Number of attempts to refresh tokens when failing for a transient reason
+const REFRESH_ATTEMPTS: u32 = 4;
+
+/// Seconds to wait before retrying a token refresh, doubled for each further retry
+const REFRESH_RETRY_BASE_SECS: u64 = 5;
+
+/// OAuth errors meaning that the refresh token is no longer valid and access must be re-authorized
+const EXPIRED_ERRORS: [&str; 2] = ["invalid_grant", "interaction_required"];
+
+/// Error response from the OAuth token endpoint
+#[derive(Deserialize)]
+struct OAuthError {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
 
 #[derive(Deserialize)]
 struct TokensImport {
@@ -46,12 +76,12 @@ impl Tokens {
         ];
 
         let client = reqwest::Client::new();
-        let resp = client
+        let request = client
             .post("https://login.microsoftonline.com/consumers/oauth2/v2.0/token")
             .header("Content-Type", "application/x-www-form-urlencoded")
             .form(&body)
-            .send()
-            .await?;
+            .send();
+        let resp = observe_http("onedrive", "redeem_code", request).await?;
 
         let json = resp.text().await?;
 
@@ -110,13 +140,17 @@ impl Tokens {
         Ok(())
     }
 
-    /// Removes the tokens file
+    /// Archives the tokens file by renaming it with the current time appended, so that a new
+    /// grant is needed while the old tokens are kept for an operator to recover
     ///
     /// # Arguments
     ///
     /// * 'tokens_path' - path to file holding tokens
-    async fn remove_tokens(&self, tokens_path: &str) -> Result<(), TokenError> {
-        fs::remove_file(tokens_path)?;
+    async fn archive_tokens(&self, tokens_path: &str) -> Result<(), TokenError> {
+        let archive_path = format!("{}.expired-{}", tokens_path, Utc::now().format("%Y%m%dT%H%M%SZ"));
+        fs::rename(tokens_path, &archive_path)?;
+        warn!("tokens archived to {}", archive_path);
+
         Ok(())
     }
     
@@ -128,12 +162,81 @@ impl Tokens {
         age > self.expires_in || age > 1800
     }
 
+    /// Returns when the refresh token is estimated to expire, which is when it has been unused
+    /// for its lifetime or when the grant reaches the maximum grant age, unless that is set to 0
+    /// Since tokens are refreshed every sync run, it is in practice the grant age that matters
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - configuration struct for OneDrive
+    pub fn refresh_expires_at(&self, config: &OneDrive) -> DateTime<Utc> {
+        let lifetime = config.refresh_token_lifetime_days.unwrap_or(DEFAULT_REFRESH_TOKEN_LIFETIME_DAYS);
+        let unused = self.refreshed_at + TimeDelta::days(lifetime as i64);
+
+        match config.max_grant_age_days.unwrap_or(DEFAULT_MAX_GRANT_AGE_DAYS) {
+            0 => unused,
+            days => unused.min(self.granted_at + TimeDelta::days(days as i64)),
+        }
+    }
+
+    /// Returns the estimated expiry of the refresh token if it is within the warning period
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - configuration struct for OneDrive
+    pub fn expiry_warning(&self, config: &OneDrive) -> Option<DateTime<Utc>> {
+        let expires_at = self.refresh_expires_at(config);
+        let warning_days = config.token_warning_days.unwrap_or(DEFAULT_TOKEN_WARNING_DAYS);
+
+        (Utc::now() + TimeDelta::days(warning_days as i64) >= expires_at).then_some(expires_at)
+    }
+
     /// Refreshes tokens using the refresh token
+    /// Transient failures, such as server errors or responses not from the token endpoint, are
+    /// retried with backoff. Only if the refresh token is rejected as expired is the tokens file
+    /// archived and access must be re-authorized
     ///
     /// # Arguments
     ///
     /// * 'config' - configuration struct for OneDrive
     pub async fn refresh_tokens(&mut self, config: &OneDrive) -> Result<(), TokenError> {
+        let mut attempt = 1;
+        let import = loop {
+            match self.request_refresh(config).await {
+                Ok(import) => break import,
+                Err(TokenError::RefreshTokenExpired) => {
+                    self.archive_tokens(&config.tokens_path).await?;
+                    return Err(TokenError::RefreshTokenExpired);
+                },
+                Err(e @ (TokenError::Request(_) | TokenError::Transient(_))) if attempt < REFRESH_ATTEMPTS => {
+                    let wait = REFRESH_RETRY_BASE_SECS << (attempt - 1);
+                    warn!("refreshing tokens failed, attempt {} of {}, retrying in {} s: {}", attempt, REFRESH_ATTEMPTS, wait, e);
+                    METRICS.retries.with_label_values(&["token_refresh"]).inc();
+                    tokio::time::sleep(Duration::from_secs(wait)).await;
+                    attempt += 1;
+                },
+                Err(e) => return Err(e),
+            }
+        };
+
+        self.token_type = import.token_type;
+        self.scope = import.scope;
+        self.expires_in = import.expires_in;
+        self.ext_expires_in = import.ext_expires_in;
+        self.access_token = import.access_token;
+        self.refresh_token = import.refresh_token;
+        self.refreshed_at = Utc::now();
+
+        self.save_tokens(&config.tokens_path).await
+    }
+
+    /// Requests new tokens using the refresh token and returns them
+    /// Redirects are not followed, since the token endpoint doesn't redirect but captive portals do
+    ///
+    /// # Arguments
+    ///
+    /// * 'config' - configuration struct for OneDrive
+    async fn request_refresh(&self, config: &OneDrive) -> Result<TokensImport, TokenError> {
         let body: [(&str, &str);5] = [
             ("client_id", &config.client_id),
             ("scope", &config.scope),
@@ -142,31 +245,46 @@ impl Tokens {
             ("client_secret", &config.client_secret),
         ];
 
-        let client = reqwest::Client::new();
-        let resp = client
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let request = client
             .post("https://login.microsoftonline.com/consumers/oauth2/v2.0/token")
             .header("Content-Type", "application/x-www-form-urlencoded")
             .form(&body)
-            .send()
-            .await?;
-
-        if !resp.status().is_success() {
-            self.remove_tokens(&config.tokens_path).await?;
-            return Err(TokenError::RefreshTokenExpired);
-        }
-        
+            .send();
+        let resp = observe_http("onedrive", "refresh_tokens", request).await?;
+        let status = resp.status();
         let json = resp.text().await?;
 
-        let import: TokensImport = serde_json::from_str(&json)?;
-
-        self.token_type = import.token_type;
-        self.scope = import.scope;
-        self.expires_in = import.expires_in;
-        self.ext_expires_in = import.ext_expires_in;
-        self.access_token = import.access_token;
-        self.refresh_token = import.refresh_token;
-        self.refreshed_at = Utc::now();
+        if status.is_success() {
+            return serde_json::from_str(&json)
+                .map_err(|e| TokenError::Transient(format!("unexpected token response: {}", e)));
+        }
 
-        self.save_tokens(&config.tokens_path).await
+        match serde_json::from_str::<OAuthError>(&json) {
+            Ok(e) if EXPIRED_ERRORS.contains(&e.error.as_str()) => {
+                error!("refresh token rejected: {}: {}", e.error, e.error_description);
+                Err(TokenError::RefreshTokenExpired)
+            },
+            Ok(e) if status.is_server_error() || status.as_u16() == 429 || e.error == "temporarily_unavailable" => {
+                Err(TokenError::Transient(format!("{} {}: {}", status, e.error, e.error_description)))
+            },
+            Ok(e) => Err(TokenError::OAuth(format!("{} {}: {}", status, e.error, e.error_description))),
+            Err(_) => Err(TokenError::Transient(format!("unexpected response {}", status))),
+        }
     }
 }
+
+/// Returns the URL to visit to re-authorize access to OneDrive, the grant route next to the
+/// code route given as redirect URI
+///
+/// # Arguments
+///
+/// * 'config' - configuration struct for OneDrive
+pub fn grant_url(config: &OneDrive) -> String {
+    reqwest::Url::parse(&config.redirect_uri)
+        .and_then(|url| url.join("grant"))
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| format!("{}/grant", config.redirect_uri.trim_end_matches('/')))
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use crate::compression::{Codec, Compressor, Decompressor};
+use crate::crypto::{Decryptor, Encryptor, MasterKey, ALGORITHM};
+use crate::errors::TransformError;
+
+/// Transforms file content on its way from OneDrive to S3
+/// Content is given in pieces as downloaded, and the transformed output can be uploaded
+/// as is, either in one go or as parts of a multipart upload. Content is first compressed
+/// and then encrypted, each step being optional
+///
+pub struct UploadTransform {
+    compressor: Option<Compressor>,
+    encryptor: Option<Encryptor>,
+    metadata: HashMap<String, String>,
+}
+
+impl UploadTransform {
+
+    /// Creates a new upload transform for one file
+    ///
+    /// # Arguments
+    ///
+    /// * 'master_key' - master key if client side encryption is enabled
+    /// * 'compressor' - compressor if the file shall be compressed
+    pub fn new(master_key: &Option<MasterKey>, compressor: Option<Compressor>) -> Result<Self, TransformError> {
+        let mut metadata: HashMap<String, String> = HashMap::new();
+
+        if let Some(compressor) = &compressor {
+            metadata.insert("compression".to_string(), compressor.codec().name().to_string());
+        }
+
+        let encryptor = match master_key {
+            Some(master_key) => {
+                let (encryptor, wrapped_key) = master_key.encryptor()?;
+                metadata.insert("cse-algorithm".to_string(), ALGORITHM.to_string());
+                metadata.insert("cse-key".to_string(), wrapped_key);
+                Some(encryptor)
+            },
+            None => None,
+        };
+
+        Ok(UploadTransform { compressor, encryptor, metadata })
+    }
+
+    /// Returns metadata needed to reverse the transform, to be stored with the object
+    ///
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Transforms a piece of content and returns whatever output is available
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - content as downloaded from OneDrive
+    pub fn update(&mut self, data: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+        let data = match self.compressor.as_mut() {
+            Some(compressor) => compressor.update(&data)?,
+            None => data,
+        };
+
+        match self.encryptor.as_mut() {
+            Some(encryptor) => Ok(encryptor.update(&data)?),
+            None => Ok(data),
+        }
+    }
+
+    /// Returns any remaining output, must be called once after the last update
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, TransformError> {
+        let data = match self.compressor.as_mut() {
+            Some(compressor) => compressor.finish()?,
+            None => Vec::new(),
+        };
+
+        match self.encryptor.as_mut() {
+            Some(encryptor) => {
+                let mut output = encryptor.update(&data)?;
+                output.extend(encryptor.finish()?);
+                Ok(output)
+            },
+            None => Ok(data),
+        }
+    }
+}
+
+/// Reverses an upload transform on content read back from S3
+///
+pub struct RestoreTransform {
+    decryptor: Option<Decryptor>,
+    decompressor: Option<Decompressor>,
+}
+
+impl RestoreTransform {
+
+    /// Creates a new restore transform for one object
+    ///
+    /// # Arguments
+    ///
+    /// * 'master_key' - master key if client side encryption is enabled
+    /// * 'metadata' - the object metadata
+    pub fn new(master_key: &Option<MasterKey>, metadata: &HashMap<String, String>) -> Result<Self, TransformError> {
+        let decryptor = match metadata.get("cse-algorithm") {
+            Some(algorithm) if algorithm == ALGORITHM => {
+                let master_key = master_key.as_ref()
+                    .ok_or(TransformError("object is encrypted but no master key is configured".to_string()))?;
+                let wrapped_key = metadata.get("cse-key")
+                    .ok_or(TransformError("object is encrypted but has no data key".to_string()))?;
+                Some(master_key.decryptor(wrapped_key)?)
+            },
+            Some(algorithm) => {
+                return Err(TransformError(format!("unknown encryption algorithm: {}", algorithm)));
+            },
+            None => None,
+        };
+
+        let decompressor = match metadata.get("compression") {
+            Some(codec) => Some(Decompressor::new(Codec::from_name(codec)?)?),
+            None => None,
+        };
+
+        Ok(RestoreTransform { decryptor, decompressor })
+    }
+
+    /// Reverses the transform of a piece of content and returns whatever output is available
+    ///
+    /// # Arguments
+    ///
+    /// * 'data' - content as read from S3
+    pub fn update(&mut self, data: Vec<u8>) -> Result<Vec<u8>, TransformError> {
+        let data = match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor.update(&data)?,
+            None => data,
+        };
+
+        match self.decompressor.as_mut() {
+            Some(decompressor) => decompressor.update(&data),
+            None => Ok(data),
+        }
+    }
+
+    /// Returns any remaining output, must be called once after the last update
+    ///
+    pub fn finish(&mut self) -> Result<Vec<u8>, TransformError> {
+        let data = match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor.finish()?,
+            None => Vec::new(),
+        };
+
+        match self.decompressor.as_mut() {
+            Some(decompressor) => {
+                let mut output = decompressor.update(&data)?;
+                output.extend(decompressor.finish()?);
+                Ok(output)
+            },
+            None => Ok(data),
+        }
+    }
+}